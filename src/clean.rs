@@ -0,0 +1,148 @@
+/// Garbage collection of stale floki-built images
+use crate::config::FlokiConfig;
+use crate::image::{list_local_images, remove_image, LocalImage};
+use anyhow::Error;
+
+use std::collections::BTreeSet;
+use std::path;
+
+/// Remove floki-built images which aren't referenced by the floki
+/// configuration files given, keeping the most recent `keep` of them.
+pub(crate) fn clean_images(
+    config_files: &[path::PathBuf],
+    default_registry: Option<&str>,
+    keep: usize,
+    dry_run: bool,
+) -> Result<(), Error> {
+    let referenced = referenced_images(config_files, default_registry)?;
+    debug!(
+        "Images referenced by known configurations: {:?}",
+        referenced
+    );
+
+    let images = list_local_images()?;
+    for image in select_images_to_remove(&images, &referenced, keep) {
+        if dry_run {
+            println!("Would remove {}", image.name);
+        } else {
+            println!("Removing {}", image.name);
+            remove_image(&image.name)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The configuration files of the known projects: the current project, if
+/// there is one, and the others given as directories or configuration files
+pub(crate) fn known_config_files(
+    current: Option<path::PathBuf>,
+    projects: &[path::PathBuf],
+) -> Vec<path::PathBuf> {
+    current
+        .into_iter()
+        .chain(projects.iter().map(|project| {
+            if project.is_dir() {
+                project.join("floki.yaml")
+            } else {
+                project.clone()
+            }
+        }))
+        .collect()
+}
+
+/// Collect the image names referenced by a set of floki configuration files.
+/// Any problem loading a configuration is an error, so that we never remove
+/// an image which might still be in use.
+fn referenced_images(
+    config_files: &[path::PathBuf],
+    default_registry: Option<&str>,
+) -> Result<BTreeSet<String>, Error> {
+    config_files
+        .iter()
+        .map(|file| {
+            let mut config = FlokiConfig::from_file(file)?;
+            config.use_default_registry(default_registry);
+            config.image.name()
+        })
+        .collect()
+}
+
+/// Decide which images should be removed. Only floki-built images which are
+/// not referenced are candidates, and the `keep` most recently created of
+/// these are retained.
+fn select_images_to_remove<'a>(
+    images: &'a [LocalImage],
+    referenced: &BTreeSet<String>,
+    keep: usize,
+) -> Vec<&'a LocalImage> {
+    let mut candidates: Vec<&LocalImage> = images
+        .iter()
+        .filter(|image| image.is_floki_built() && !referenced.contains(&image.name))
+        .collect();
+    // Most recent first. docker lists every image's creation time in the
+    // same zone, so these sort correctly as text.
+    candidates.sort_by(|a, b| b.created.cmp(&a.created));
+    candidates.into_iter().skip(keep).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(name: &str, created: &str) -> LocalImage {
+        LocalImage {
+            name: name.into(),
+            id: format!("id-{}", name),
+            created: created.into(),
+        }
+    }
+
+    fn names(images: Vec<&LocalImage>) -> Vec<&str> {
+        images.iter().map(|i| i.name.as_str()).collect()
+    }
+
+    #[test]
+    fn test_select_images_to_remove() {
+        let images = vec![
+            image("old:floki", "2021-01-01 00:00:00 +0000 UTC"),
+            image("debian:sid", "2021-06-01 00:00:00 +0000 UTC"),
+            image("current:floki", "2021-03-01 00:00:00 +0000 UTC"),
+            image("newer:floki", "2022-01-01 00:00:00 +0000 UTC"),
+            image("newest:floki", "2022-02-01 00:00:00 +0000 UTC"),
+        ];
+        let referenced: BTreeSet<String> = vec!["current:floki".to_string()].into_iter().collect();
+
+        assert_eq!(
+            names(select_images_to_remove(&images, &referenced, 0)),
+            vec!["newest:floki", "newer:floki", "old:floki"]
+        );
+        assert_eq!(
+            names(select_images_to_remove(&images, &referenced, 2)),
+            vec!["old:floki"]
+        );
+        assert!(select_images_to_remove(&images, &referenced, 5).is_empty());
+    }
+
+    #[test]
+    fn test_known_config_files() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let current = path::PathBuf::from("/current/custom.yaml");
+        let projects = vec![
+            directory.path().to_path_buf(),
+            path::PathBuf::from("/elsewhere/floki.yaml"),
+        ];
+
+        // The current project is kept even when others are given
+        assert_eq!(
+            known_config_files(Some(current.clone()), &projects),
+            vec![
+                current,
+                directory.path().join("floki.yaml"),
+                projects[1].clone()
+            ]
+        );
+        assert_eq!(known_config_files(None, &projects).len(), 2);
+        Ok(())
+    }
+}
@@ -1,17 +1,85 @@
 /// Description of the CLI interface to floki
 use std::path;
+use std::str::FromStr;
 use structopt::StructOpt;
 
+pub use crate::variables::ExportShell;
+
 /// Subcommands of the main floki command
 #[derive(Debug, StructOpt)]
-pub(crate) enum Subcommand {
+pub enum Subcommand {
     /// Run a command within the container
     #[structopt(name = "run")]
-    Run { command: Vec<String> },
+    Run {
+        command: Vec<String>,
+
+        /// Read the command to run as a script from a file ("-" for stdin).
+        /// The script is run as it is, without filling in placeholders
+        #[structopt(long = "command-file", conflicts_with = "command")]
+        command_file: Option<path::PathBuf>,
+
+        /// Stop the script at the first failing command (as with "set -e")
+        #[structopt(long = "exit-on-error", requires = "command-file")]
+        exit_on_error: bool,
+
+        /// Run the command once in each image in image_matrix
+        #[structopt(long = "matrix")]
+        matrix: bool,
 
-    /// Pull the image in the configuration file
+        /// Also write the command's output to this file. With --matrix, each
+        /// image gets its own numbered file, e.g. build.0.log
+        #[structopt(long = "output", name = "OUTPUT")]
+        output: Option<path::PathBuf>,
+    },
+
+    /// Pull the image in the configuration file, and print its digest
     #[structopt(name = "pull")]
-    Pull {},
+    Pull {
+        /// Only print the digest of the pulled image
+        #[structopt(long = "quiet", short = "q")]
+        quiet: bool,
+    },
+
+    /// Remove stale floki-built images
+    #[structopt(name = "clean")]
+    Clean {
+        /// Remove floki-built images that aren't used by any known floki
+        /// project (the current project, and any given with --project)
+        #[structopt(long = "images")]
+        images: bool,
+
+        /// Another floki project directory or configuration file whose
+        /// image should be kept, as well as the current project's. May be
+        /// given multiple times.
+        #[structopt(long = "project", name = "DIR")]
+        projects: Vec<path::PathBuf>,
+
+        /// Keep this many of the most recently created unused images
+        #[structopt(long = "keep", default_value = "0")]
+        keep: usize,
+
+        /// List the images which would be removed without removing them
+        #[structopt(long = "dry-run")]
+        dry_run: bool,
+    },
+
+    /// Print shell statements which set the environment variables floki
+    /// would set in the container
+    #[structopt(name = "shell-env")]
+    ShellEnv {
+        /// The shell to write statements for.  Choose from: sh, bash, zsh, fish, powershell
+        #[structopt(name = "SHELL", default_value = "sh", possible_values = &["sh", "bash", "zsh", "fish", "powershell"])]
+        shell: ExportShell,
+    },
+
+    /// Write a commented starter floki.yaml in the current directory. Use
+    /// --image to choose the image.
+    #[structopt(name = "init")]
+    Init {
+        /// Replace an existing floki.yaml
+        #[structopt(long = "force")]
+        force: bool,
+    },
 
     /// Generate shell completions to stdout.
     #[structopt(name = "completion")]
@@ -22,23 +90,93 @@ pub(crate) enum Subcommand {
     },
 }
 
+/// How floki reports errors
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorFormat {
+    /// A log line for humans
+    Human,
+    /// A JSON object for tools
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "human" => Ok(ErrorFormat::Human),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format '{}'", s)),
+        }
+    }
+}
+
 /// Main CLI interface
 #[derive(Debug, StructOpt)]
 #[structopt(name = "floki", about = "The interactive container launcher.")]
-pub(crate) struct Cli {
+pub struct Cli {
     /// Use the specified config instead of searching the tree for a
     /// "floki.yaml" file.
     #[structopt(long = "config", short = "c")]
-    pub(crate) config_file: Option<path::PathBuf>,
+    pub config_file: Option<path::PathBuf>,
+
+    /// Run floki as if it was launched in this directory
+    #[structopt(long = "from", name = "DIR")]
+    pub from: Option<path::PathBuf>,
+
+    /// Merge the named profile from the configuration file over the rest
+    /// of the configuration
+    #[structopt(long = "profile", name = "PROFILE", env = "FLOKI_PROFILE")]
+    pub profile: Option<String>,
+
+    /// Forward the environment variables named in this file, one per
+    /// line, in addition to those in forward_env
+    #[structopt(long = "env-passthrough-file", name = "FILE")]
+    pub env_passthrough_file: Option<path::PathBuf>,
 
-    /// Deprecated, and no longer has any effect.
+    /// Run this image instead of the configured one. This can be used
+    /// without a configuration file, to run the image with the defaults.
+    #[structopt(long = "image", name = "IMAGE", global = true)]
+    pub image: Option<String>,
+
+    /// Use the image of the project with this configuration file, rather
+    /// than building or pulling this project's own
+    #[structopt(long = "reuse-image-from", name = "CONFIG")]
+    pub reuse_image_from: Option<path::PathBuf>,
+
+    /// Removed. Passing this is an error.
     #[structopt(long = "local", short = "l", hidden = true)]
-    pub(crate) local: bool,
+    pub local: bool,
 
     /// Logging verbosity level
     #[structopt(short = "v", parse(from_occurrences))]
-    pub(crate) verbosity: u8,
+    pub verbosity: u8,
+
+    /// Resolve the configuration and print the command floki would run in
+    /// the container, without running anything
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Show the output of docker commands run to prepare the container,
+    /// such as image builds and pulls, rather than only showing it if they
+    /// fail
+    #[structopt(long = "verbose-docker")]
+    pub verbose_docker: bool,
+
+    /// Don't check the docker client is new enough for the configured
+    /// features
+    #[structopt(long = "skip-version-check")]
+    pub skip_version_check: bool,
+
+    /// Prefix log lines with the time elapsed since floki started
+    #[structopt(long = "timestamps")]
+    pub timestamps: bool,
+
+    /// How to report errors: "human" (the default) or "json", which
+    /// writes a JSON object describing the error to stderr
+    #[structopt(long = "error-format", default_value = "human", possible_values = &["human", "json"])]
+    pub error_format: ErrorFormat,
 
     #[structopt(subcommand)]
-    pub(crate) subcommand: Option<Subcommand>,
+    pub subcommand: Option<Subcommand>,
 }
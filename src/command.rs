@@ -1,26 +1,162 @@
 use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
 use anyhow::Error;
 use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::path;
-use std::process::{Command, Stdio};
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Mutex;
+use std::thread;
+
+/// What to do with the output of the docker commands floki runs to
+/// prepare the container, such as builds and pulls
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DockerOutput {
+    /// Show the output as it is written
+    Inherit,
+    /// Keep the output, and only show it if the command fails
+    Capture,
+}
+
+impl DockerOutput {
+    /// Show docker's output if `--verbose-docker` was given
+    pub fn from_flag(verbose: bool) -> Self {
+        if verbose {
+            DockerOutput::Inherit
+        } else {
+            DockerOutput::Capture
+        }
+    }
+
+    fn stdio(self) -> Stdio {
+        match self {
+            DockerOutput::Inherit => Stdio::inherit(),
+            DockerOutput::Capture => Stdio::piped(),
+        }
+    }
+}
+
+/// Run a command to completion, handling its output according to
+/// `output`. Captured output is logged if the command fails.
+pub fn run_with_docker_output(
+    command: &mut Command,
+    output: DockerOutput,
+) -> Result<ExitStatus, io::Error> {
+    let result = run_collecting_output(command, output)?;
+    if output == DockerOutput::Capture && !result.status.success() {
+        error!(
+            "Output of failed command:\n{}{}",
+            String::from_utf8_lossy(&result.stdout),
+            String::from_utf8_lossy(&result.stderr)
+        );
+    }
+    Ok(result.status)
+}
+
+fn run_collecting_output(
+    command: &mut Command,
+    output: DockerOutput,
+) -> Result<std::process::Output, io::Error> {
+    command
+        .stdout(output.stdio())
+        .stderr(output.stdio())
+        .output()
+}
+
+/// Arguments to `docker run` for a container attached to our stdin. A
+/// terminal is only allocated if stdin is one, as docker refuses to
+/// allocate one otherwise - e.g. when a script is piped to floki.
+fn run_args(terminal: bool) -> [&'static str; 3] {
+    if terminal {
+        ["run", "--rm", "-it"]
+    } else {
+        ["run", "--rm", "-i"]
+    }
+}
+
+fn stdin_is_terminal() -> bool {
+    nix::unistd::isatty(0).unwrap_or(false)
+}
+
+/// Copy everything read from `reader` to both `terminal` and `file` as it
+/// arrives. The file is shared between the streams being copied.
+fn tee<R: Read, T: Write, F: Write>(
+    mut reader: R,
+    terminal: &mut T,
+    file: &Mutex<F>,
+) -> io::Result<()> {
+    let mut buffer = [0; 8192];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        terminal.write_all(&buffer[..read])?;
+        terminal.flush()?;
+        lock(file).write_all(&buffer[..read])?;
+    }
+}
+
+fn lock<F>(file: &Mutex<F>) -> std::sync::MutexGuard<'_, F> {
+    // A copy which panicked has already been reported
+    file.lock().unwrap_or_else(|e| e.into_inner())
+}
 
 #[derive(Debug, Clone)]
 pub struct DockerCommandBuilder {
     name: String,
     volumes: Vec<OsString>,
     environment: Vec<OsString>,
+    secret_environment: Vec<(OsString, SecretValue)>,
     switches: Vec<OsString>,
+    stop_timeout: Option<u64>,
+    restart: Option<String>,
     image: String,
 }
 
+/// A value which must not be written to logs or passed on the command line
+#[derive(Clone)]
+struct SecretValue(OsString);
+
+impl fmt::Debug for SecretValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<redacted>")
+    }
+}
+
 #[derive(Debug)]
 pub struct DaemonHandle {
     name: String,
+    stop_timeout: Option<u64>,
+    /// Whether the container must be removed once stopped, because docker
+    /// wasn't asked to remove it
+    remove: bool,
 }
 
 impl DaemonHandle {
     fn from_builder(builder: DockerCommandBuilder) -> Self {
-        DaemonHandle { name: builder.name }
+        DaemonHandle {
+            name: builder.name,
+            stop_timeout: builder.stop_timeout,
+            remove: builder.restart.is_some(),
+        }
+    }
+
+    /// Arguments to docker to stop the daemon. The container is killed
+    /// outright unless a stop timeout has been given.
+    fn stop_args(&self) -> Vec<String> {
+        match self.stop_timeout {
+            Some(timeout) => vec![
+                "stop".into(),
+                "-t".into(),
+                timeout.to_string(),
+                self.name.clone(),
+            ],
+            None => vec!["kill".into(), self.name.clone()],
+        }
     }
 }
 
@@ -28,7 +164,7 @@ impl Drop for DaemonHandle {
     fn drop(&mut self) {
         info!("Stopping daemon docker container '{}'", self.name);
         Command::new("docker")
-            .args(&["kill", &self.name])
+            .args(self.stop_args())
             .stdin(Stdio::null())
             .stdout(Stdio::null())
             .stderr(Stdio::null())
@@ -36,6 +172,17 @@ impl Drop for DaemonHandle {
             .expect("Unable to kill docker container")
             .wait()
             .expect("Unable to wait for docker container to die");
+        if self.remove {
+            Command::new("docker")
+                .args(["rm", &self.name])
+                .stdin(Stdio::null())
+                .stdout(Stdio::null())
+                .stderr(Stdio::null())
+                .spawn()
+                .expect("Unable to remove docker container")
+                .wait()
+                .expect("Unable to wait for docker container to be removed");
+        }
     }
 }
 
@@ -47,10 +194,9 @@ impl DockerCommandBuilder {
         );
 
         let mut command = Command::new("docker")
-            .args(&["run", "--rm", "-it"])
-            .args(&self.build_volume_switches())
-            .args(self.build_environment_switches())
-            .args(self.build_docker_switches())
+            .args(run_args(stdin_is_terminal()))
+            .args(self.docker_args())
+            .envs(self.build_secret_environment())
             .arg(&self.image)
             .args(command)
             .stdout(Stdio::inherit())
@@ -75,14 +221,69 @@ impl DockerCommandBuilder {
         }
     }
 
+    /// Run a command in the container, copying its stdout and stderr to
+    /// `output` as well as the terminal. The container is run without a
+    /// terminal, so the two streams stay separate.
+    pub fn run_with_output(&self, command: &[&str], output: &path::Path) -> Result<(), Error> {
+        debug!(
+            "Spawning docker command with configuration: {:?} args: {:?} output: {:?}",
+            self, command, output
+        );
+        let output_error = |error| FlokiError::ProblemWritingOutputFile {
+            name: output.display().to_string(),
+            error,
+        };
+        let file = Mutex::new(fs::File::create(output).map_err(output_error)?);
+
+        let mut child = Command::new("docker")
+            .args(["run", "--rm", "-i"])
+            .args(self.docker_args())
+            .envs(self.build_secret_environment())
+            .arg(&self.image)
+            .args(command)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .stdin(Stdio::inherit())
+            .spawn()
+            .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+        let copied = thread::scope(|scope| {
+            let copying_stdout = scope.spawn(|| tee(stdout, &mut io::stdout(), &file));
+            let copied_stderr = tee(stderr, &mut io::stderr(), &file);
+            copying_stdout
+                .join()
+                .expect("copying stdout panicked")
+                .and(copied_stderr)
+        });
+
+        let exit_status = child
+            .wait()
+            .map_err(|e| FlokiError::FailedToCompleteDockerCommand { error: e })?;
+        copied
+            .and_then(|()| lock(&file).flush())
+            .map_err(output_error)?;
+        if exit_status.success() {
+            Ok(())
+        } else {
+            Err(FlokiError::RunContainerFailed {
+                exit_status: FlokiSubprocessExitStatus {
+                    process_description: "docker run".into(),
+                    exit_status,
+                },
+            }
+            .into())
+        }
+    }
+
     pub fn start_as_daemon(self, command: &[&str]) -> Result<DaemonHandle, Error> {
         debug!("Starting daemon container '{}'", self.name);
         let exit_status = Command::new("docker")
-            .args(&["run", "--rm"])
-            .args(&["--name", &self.name])
-            .args(&self.build_volume_switches())
-            .args(self.build_environment_switches())
-            .args(self.build_docker_switches())
+            .args(self.daemon_run_args())
+            .args(["--name", &self.name])
+            .args(self.docker_args())
+            .envs(self.build_secret_environment())
             .arg("-d")
             .arg(&self.image)
             .args(command)
@@ -112,7 +313,10 @@ impl DockerCommandBuilder {
             name: uuid::Uuid::new_v4().to_string(),
             volumes: Vec::new(),
             environment: Vec::new(),
+            secret_environment: Vec::new(),
             switches: Vec::new(),
+            stop_timeout: None,
+            restart: None,
             image: image.into(),
         }
     }
@@ -121,23 +325,94 @@ impl DockerCommandBuilder {
         &self.name
     }
 
+    /// Give the container a name, rather than leaving docker to pick one
+    pub fn set_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = name.into();
+        let name = self.name.clone();
+        self.add_docker_switch("--name").add_docker_switch(name)
+    }
+
     pub fn add_volume(mut self, spec: (&path::PathBuf, &path::PathBuf)) -> Self {
         let (src, dst) = spec;
         self.volumes.push(Self::volume_mapping(src, dst));
         self
     }
 
+    pub fn add_read_only_volume(mut self, spec: (&path::PathBuf, &path::PathBuf)) -> Self {
+        let (src, dst) = spec;
+        let mut mapping = Self::volume_mapping(src, dst);
+        mapping.push(":ro");
+        self.volumes.push(mapping);
+        self
+    }
+
+    /// Mount an empty anonymous volume, shadowing whatever would otherwise
+    /// be at `dst`
+    pub fn add_anonymous_volume(mut self, dst: &path::Path) -> Self {
+        self.volumes.push(dst.as_os_str().into());
+        self
+    }
+
     pub fn add_environment<V: AsRef<OsStr>, B: AsRef<OsStr>>(mut self, var: V, bind: B) -> Self {
         self.environment.push("-e".into());
         self.environment.push(Self::environment_mapping(var, bind));
         self
     }
 
+    /// Add an environment variable whose value is passed to docker through
+    /// its own environment, so it never appears on the command line
+    pub fn add_secret_environment<V: AsRef<OsStr>, B: AsRef<OsStr>>(
+        mut self,
+        var: V,
+        value: B,
+    ) -> Self {
+        self.environment.push("-e".into());
+        self.environment.push(var.as_ref().into());
+        self.secret_environment
+            .push((var.as_ref().into(), SecretValue(value.as_ref().into())));
+        self
+    }
+
     pub fn add_docker_switch<S: AsRef<OsStr>>(mut self, switch: S) -> Self {
         self.switches.push(switch.as_ref().into());
         self
     }
 
+    /// Set how long docker waits for the container to stop before killing it
+    pub fn set_stop_timeout(mut self, timeout: u64) -> Self {
+        self.stop_timeout = Some(timeout);
+        self.add_docker_switch("--stop-timeout")
+            .add_docker_switch(timeout.to_string())
+    }
+
+    /// Set the restart policy for the container. This only applies when
+    /// the container is started as a daemon.
+    pub fn set_restart_policy<S: Into<String>>(mut self, policy: S) -> Self {
+        self.restart = Some(policy.into());
+        self
+    }
+
+    /// The start of the docker command for running a daemon. docker can't
+    /// remove a container with a restart policy automatically, so these are
+    /// removed when the daemon is stopped instead.
+    fn daemon_run_args(&self) -> Vec<&str> {
+        match &self.restart {
+            Some(policy) => vec!["run", "--restart", policy],
+            None => vec!["run", "--rm"],
+        }
+    }
+
+    /// Set the size of `/dev/shm` in the container
+    pub fn set_shm_size<S: AsRef<OsStr>>(self, size: S) -> Self {
+        self.add_docker_switch("--shm-size").add_docker_switch(size)
+    }
+
+    /// Set the signal docker sends to stop the container
+    pub fn set_stop_signal<S: AsRef<OsStr>>(self, signal: S) -> Self {
+        self.add_docker_switch("--stop-signal")
+            .add_docker_switch(signal)
+    }
+
     pub fn set_working_directory<S: AsRef<OsStr>>(self, directory: S) -> Self {
         let mut cmd = self;
         cmd = cmd.add_docker_switch("-w");
@@ -145,6 +420,18 @@ impl DockerCommandBuilder {
         cmd
     }
 
+    /// The arguments given to `docker run` ahead of the image name
+    pub fn docker_args(&self) -> Vec<&OsStr> {
+        let mut args = self.build_volume_switches();
+        args.extend(
+            self.build_environment_switches()
+                .iter()
+                .map(|s| s.as_os_str()),
+        );
+        args.extend(self.build_docker_switches().iter().map(|s| s.as_os_str()));
+        args
+    }
+
     fn build_volume_switches(&self) -> Vec<&OsStr> {
         let mut switches = Vec::new();
         for mapping in self.volumes.iter() {
@@ -175,6 +462,12 @@ impl DockerCommandBuilder {
     fn build_docker_switches(&self) -> &Vec<OsString> {
         &self.switches
     }
+
+    fn build_secret_environment(&self) -> impl Iterator<Item = (&OsStr, &OsStr)> {
+        self.secret_environment
+            .iter()
+            .map(|(var, value)| (var.as_os_str(), value.0.as_os_str()))
+    }
 }
 
 pub fn enable_forward_ssh_agent(
@@ -188,12 +481,157 @@ pub fn enable_forward_ssh_agent(
         .add_volume((&dir, &dir))
 }
 
+pub fn enable_docker_socket(
+    command: DockerCommandBuilder,
+    socket: &path::Path,
+) -> DockerCommandBuilder {
+    debug!("Forwarding docker socket {:?}", socket);
+    let internal = path::PathBuf::from(crate::dind::DEFAULT_DOCKER_SOCKET);
+    command
+        .add_volume((&socket.to_path_buf(), &internal))
+        .add_environment("DOCKER_HOST", format!("unix://{}", internal.display()))
+}
+
 pub fn enable_docker_in_docker(
     command: DockerCommandBuilder,
     dind: &crate::dind::Dind,
 ) -> Result<DockerCommandBuilder, Error> {
     Ok(command
         .add_docker_switch("--link")
-        .add_docker_switch(&format!("{}:floki-docker", dind.name()))
+        .add_docker_switch(format!("{}:floki-docker", dind.name()))
         .add_environment("DOCKER_HOST", "tcp://floki-docker:2375"))
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_docker_output() -> Result<(), Error> {
+        let script = ["-c", "echo out; echo err >&2; exit 3"];
+
+        let captured =
+            run_collecting_output(Command::new("sh").args(script), DockerOutput::Capture)?;
+        assert_eq!(captured.status.code(), Some(3));
+        assert_eq!(captured.stdout, b"out\n");
+        assert_eq!(captured.stderr, b"err\n");
+
+        // Inherited output goes straight to our own stdout and stderr
+        let inherited =
+            run_collecting_output(Command::new("sh").args(script), DockerOutput::Inherit)?;
+        assert_eq!(inherited.status.code(), Some(3));
+        assert!(inherited.stdout.is_empty());
+        assert!(inherited.stderr.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_args() {
+        assert_eq!(run_args(true), ["run", "--rm", "-it"]);
+        // e.g. floki run --command-file - < script.sh
+        assert_eq!(run_args(false), ["run", "--rm", "-i"]);
+    }
+
+    #[test]
+    fn test_tee() -> Result<(), Error> {
+        // Output isn't necessarily text
+        let data: Vec<u8> = (0..=255).cycle().take(20000).collect();
+        let mut terminal = Vec::new();
+        let file = Mutex::new(Vec::new());
+
+        tee(&data[..], &mut terminal, &file)?;
+        assert_eq!(terminal, data);
+        assert_eq!(file.lock().unwrap().as_slice(), &data[..]);
+
+        // A second stream is appended to the same file
+        let mut other_terminal = Vec::new();
+        tee(&b"error\n"[..], &mut other_terminal, &file)?;
+        assert_eq!(other_terminal, b"error\n");
+        let file = file.into_inner().unwrap();
+        assert_eq!(file.len(), data.len() + 6);
+        assert!(file.ends_with(b"error\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_with_docker_output() -> Result<(), Error> {
+        // Output would be shown by the test harness if it wasn't captured,
+        // but either way the exit status is reported
+        for output in [DockerOutput::Inherit, DockerOutput::Capture] {
+            let ok = run_with_docker_output(Command::new("sh").args(["-c", "echo hi"]), output)?;
+            assert!(ok.success());
+            let failing =
+                run_with_docker_output(Command::new("sh").args(["-c", "exit 3"]), output)?;
+            assert!(!failing.success());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_timeout() {
+        let builder = DockerCommandBuilder::new("image").set_stop_timeout(30);
+        let args: Vec<&OsStr> = builder.docker_args();
+        assert!(args.windows(2).any(|w| w == ["--stop-timeout", "30"]));
+
+        let handle = DaemonHandle::from_builder(builder);
+        assert_eq!(handle.stop_args()[..3], ["stop", "-t", "30"]);
+        // Don't try and stop a container which was never started
+        std::mem::forget(handle);
+    }
+
+    #[test]
+    fn test_anonymous_volume() {
+        let builder = DockerCommandBuilder::new("image")
+            .add_volume((&"/host".into(), &"/src".into()))
+            .add_anonymous_volume(path::Path::new("/src/target"));
+        assert_eq!(
+            builder.docker_args(),
+            ["-v", "/host:/src", "-v", "/src/target"]
+        );
+    }
+
+    #[test]
+    fn test_shm_size() {
+        let builder = DockerCommandBuilder::new("image").set_shm_size("2g");
+        assert_eq!(builder.docker_args(), ["--shm-size", "2g"]);
+    }
+
+    #[test]
+    fn test_stop_signal() {
+        let builder = DockerCommandBuilder::new("image").set_stop_signal("SIGQUIT");
+        assert!(builder
+            .docker_args()
+            .windows(2)
+            .any(|w| w == ["--stop-signal", "SIGQUIT"]));
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        let builder = DockerCommandBuilder::new("image");
+        assert_eq!(builder.daemon_run_args(), ["run", "--rm"]);
+
+        let builder = builder.set_restart_policy("on-failure");
+        assert_eq!(
+            builder.daemon_run_args(),
+            ["run", "--restart", "on-failure"]
+        );
+        // Containers run in the foreground are always removed on exit
+        assert!(!builder.docker_args().contains(&OsStr::new("--restart")));
+
+        let handle = DaemonHandle::from_builder(builder);
+        assert!(handle.remove);
+        std::mem::forget(handle);
+    }
+
+    #[test]
+    fn test_no_stop_timeout() {
+        let builder = DockerCommandBuilder::new("image");
+        assert!(!builder
+            .docker_args()
+            .contains(&OsStr::new("--stop-timeout")));
+
+        let handle = DaemonHandle::from_builder(builder);
+        assert_eq!(handle.stop_args()[0], "kill");
+        std::mem::forget(handle);
+    }
+}
@@ -2,13 +2,14 @@
 use crate::errors;
 use crate::image;
 use anyhow::Error;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 
 use std::collections::BTreeMap;
 use std::fs::File;
 use std::path;
+use std::time::Duration;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub(crate) enum Shell {
     Shell(String),
@@ -31,11 +32,20 @@ impl Shell {
     }
 }
 
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum DindMode {
+    /// Forward the host's docker socket instead of running a linked
+    /// docker-in-docker container
+    Socket,
+}
+
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub(crate) enum DindConfig {
     Toggle(bool),
     Image { image: String },
+    Mode(DindMode),
 }
 
 impl DindConfig {
@@ -44,7 +54,18 @@ impl DindConfig {
     }
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+/// What to do when an image was built for a different architecture to
+/// the one expected
+pub(crate) enum PlatformCheck {
+    /// Warn, and run the image anyway
+    Warn,
+    /// Refuse to run the image
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 /// The Volume structure captures configuration for floki volumes
 pub(crate) struct Volume {
     #[serde(default = "default_to_false")]
@@ -55,6 +76,44 @@ pub(crate) struct Volume {
     /// The mount path is the path at which the volume is mounted
     /// inside the floki container.
     pub(crate) mount: path::PathBuf,
+    #[serde(default = "Vec::new")]
+    /// Host directories to layer at the mount path with an overlay
+    /// filesystem. Later directories take precedence over earlier ones,
+    /// and changes made in the container are kept in the volume.
+    pub(crate) overlay: Vec<path::PathBuf>,
+    /// A host directory to mount, in place of a directory managed by
+    /// floki. Relative paths are relative to the floki config file.
+    pub(crate) source: Option<path::PathBuf>,
+    #[serde(default = "default_to_false")]
+    /// An optional volume is skipped if its source doesn't exist, rather
+    /// than being an error.
+    pub(crate) optional: bool,
+    #[serde(default = "default_to_false")]
+    /// Give the mount path to the container user before the container
+    /// starts, for directories which would otherwise be owned by root.
+    pub(crate) chown: bool,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// A credential stored in the host's keyring
+pub(crate) struct KeyringEntry {
+    /// The service the credential is stored under
+    pub(crate) service: String,
+    /// The user the credential is stored under
+    pub(crate) user: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+/// The Secret structure captures configuration for secrets made
+/// available in the floki container
+pub(crate) struct Secret {
+    /// Where the secret is read from on the host
+    pub(crate) keyring: KeyringEntry,
+    /// If set, the secret is mounted as a read-only file at this path
+    /// inside the floki container. Otherwise it is exposed as an
+    /// environment variable named after the secret.
+    pub(crate) mount: Option<path::PathBuf>,
 }
 
 #[derive(Debug, PartialEq, Serialize, Deserialize)]
@@ -78,6 +137,11 @@ pub(crate) struct FlokiConfig {
     pub(crate) image: image::Image,
     #[serde(default = "Vec::new")]
     pub(crate) init: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) pre_run: Vec<String>,
+    /// Programs which must be on the host's PATH to launch the container
+    #[serde(default = "Vec::new")]
+    pub(crate) requires_host_tools: Vec<String>,
     #[serde(default = "default_shell")]
     pub(crate) shell: Shell,
     #[serde(default = "default_mount")]
@@ -88,16 +152,160 @@ pub(crate) struct FlokiConfig {
     pub(crate) forward_ssh_agent: bool,
     #[serde(default = "DindConfig::deactivated")]
     pub(crate) dind: DindConfig,
+    pub(crate) docker_socket: Option<path::PathBuf>,
     #[serde(default = "default_to_false")]
     pub(crate) forward_user: bool,
     #[serde(default = "BTreeMap::new")]
     pub(crate) volumes: BTreeMap<String, Volume>,
+    #[serde(default = "Vec::new")]
+    pub(crate) cache: Vec<String>,
     #[serde(default = "default_entrypoint")]
     pub(crate) entrypoint: Entrypoint,
+    #[serde(default = "BTreeMap::new")]
+    pub(crate) secrets: BTreeMap<String, Secret>,
+    pub(crate) stop_timeout: Option<u64>,
+    pub(crate) stop_signal: Option<String>,
+    pub(crate) restart: Option<String>,
+    pub(crate) log_driver: Option<String>,
+    #[serde(default = "BTreeMap::new")]
+    pub(crate) log_opts: BTreeMap<String, String>,
+    pub(crate) shm_size: Option<String>,
+    /// Pull a tagged image again once the local copy is this old, e.g. `7d`
+    #[serde(default, deserialize_with = "deserialize_refresh_tag_after")]
+    pub(crate) refresh_tag_after: Option<Duration>,
+    pub(crate) pid: Option<String>,
+    pub(crate) ipc: Option<String>,
+    pub(crate) umask: Option<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) dns: Vec<String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) add_hosts: Vec<String>,
+    #[serde(default = "default_to_false")]
+    pub(crate) mount_git_common: bool,
+    pub(crate) mount_config: Option<path::PathBuf>,
+    /// Directories added to the front of PATH in the container, relative
+    /// to the mount
+    #[serde(default = "Vec::new")]
+    pub(crate) prepend_path: Vec<path::PathBuf>,
+    /// Container paths kept in docker volumes across runs
+    #[serde(default = "Vec::new")]
+    pub(crate) persist_paths: Vec<path::PathBuf>,
+    /// Paths within the project which are hidden from the container
+    #[serde(default = "Vec::new")]
+    pub(crate) exclude: Vec<path::PathBuf>,
+    #[serde(default = "default_to_false")]
+    pub(crate) persist_history: bool,
+    #[serde(default = "BTreeMap::new")]
+    pub(crate) env: BTreeMap<String, String>,
+    #[serde(default = "Vec::new")]
+    pub(crate) forward_env: Vec<String>,
+    pub(crate) env_file: Option<path::PathBuf>,
+    pub(crate) env_passthrough_file: Option<path::PathBuf>,
+    #[serde(default = "default_to_false")]
+    pub(crate) proxy_from_host: bool,
+    pub(crate) motd: Option<String>,
+    pub(crate) command_prefix: Option<String>,
+    /// Another project's configuration, whose image is used instead of
+    /// this one's
+    pub(crate) reuse_image_from: Option<path::PathBuf>,
+    /// The directory of the configuration the image was reused from, which
+    /// its build context and files are relative to
+    #[serde(skip)]
+    pub(crate) image_root: Option<path::PathBuf>,
+    /// Images to run `floki run --matrix` commands in
+    #[serde(default = "Vec::new")]
+    pub(crate) image_matrix: Vec<String>,
+    /// Variables substituted for `{{name}}` placeholders in `floki run`
+    /// commands
+    #[serde(default = "BTreeMap::new")]
+    pub(crate) template_vars: BTreeMap<String, String>,
+    pub(crate) default_registry: Option<String>,
+    pub(crate) platform: Option<String>,
+    #[serde(default = "default_platform_check")]
+    pub(crate) platform_check: PlatformCheck,
+    /// Named groups of overrides, merged over the rest of the
+    /// configuration when selected
+    #[serde(default = "BTreeMap::new")]
+    pub(crate) profiles: BTreeMap<String, serde_yaml::Value>,
 }
 
 impl FlokiConfig {
     pub fn from_file(file: &path::Path) -> Result<FlokiConfig, Error> {
+        Self::from_file_with_profile(file, None)
+    }
+
+    /// The configuration for running an image without a configuration
+    /// file, with everything else left at its default
+    pub fn for_image(image: &str) -> Result<FlokiConfig, Error> {
+        let mut config = serde_yaml::Mapping::new();
+        config.insert("image".into(), image.into());
+        Ok(serde_yaml::from_value(serde_yaml::Value::Mapping(config))?)
+    }
+
+    /// Load a configuration file, merging the named profile over it
+    pub fn from_file_with_profile(
+        file: &path::Path,
+        profile: Option<&str>,
+    ) -> Result<FlokiConfig, Error> {
+        let mut config = Self::read_file(file, profile)?;
+        config.resolve_reused_image(file)?;
+        Ok(config)
+    }
+
+    /// Replace the image with the one used by the configuration named in
+    /// `reuse_image_from`, following any references that configuration
+    /// makes in turn. Relative references are taken relative to the file
+    /// making them. The image is kept as that configuration gives it, so
+    /// it can be built or loaded from the other project's directory.
+    pub(crate) fn resolve_reused_image(&mut self, file: &path::Path) -> Result<(), Error> {
+        let mut current = canonical_config_path(file)?;
+        let mut chain = vec![current.clone()];
+        let mut reference = self.reuse_image_from.clone();
+        let mut image = None;
+
+        while let Some(other) = reference {
+            // Replacing the file name resolves a relative reference against
+            // the directory of the referring file
+            let other = canonical_config_path(&current.with_file_name(other))?;
+            if chain.contains(&other) {
+                chain.push(other);
+                let chain: Vec<String> = chain.iter().map(|f| f.display().to_string()).collect();
+                return Err(errors::FlokiUserError::ReuseImageCycle {
+                    chain: chain.join(" -> "),
+                }
+                .into());
+            }
+            let mut config = Self::read_file(&other, None)?;
+            config.use_default_registry(None);
+            reference = config.reuse_image_from;
+            image = Some(config.image);
+            chain.push(other.clone());
+            current = other;
+        }
+
+        if let Some(image) = image {
+            debug!("Reusing the image {} from {:?}", image.name()?, current);
+            self.image = image;
+            self.image_root = current.parent().map(path::Path::to_path_buf);
+        }
+        Ok(())
+    }
+
+    /// Resolve the image names against the configured default registry,
+    /// or otherwise `fallback` (from `FLOKI_REGISTRY`), if either is set
+    pub(crate) fn use_default_registry(&mut self, fallback: Option<&str>) {
+        let registry = match self.default_registry.as_deref().or(fallback) {
+            Some(registry) => registry.to_string(),
+            None => return,
+        };
+        self.image.use_default_registry(&registry);
+        for image in self.image_matrix.iter_mut() {
+            *image = image::qualify_image_name(image, &registry);
+        }
+    }
+
+    /// Read a configuration file, without following `reuse_image_from`
+    fn read_file(file: &path::Path, profile: Option<&str>) -> Result<FlokiConfig, Error> {
         debug!("Reading configuration file: {:?}", file);
 
         let f = File::open(file).map_err(|e| errors::FlokiError::ProblemOpeningConfigYaml {
@@ -105,12 +313,19 @@ impl FlokiConfig {
             error: e,
         })?;
 
-        let mut config: FlokiConfig = serde_yaml::from_reader(f).map_err(|e| {
-            errors::FlokiError::ProblemParsingConfigYaml {
-                name: file.display().to_string(),
-                error: e,
+        let parse_error = |e| errors::FlokiError::ProblemParsingConfigYaml {
+            name: file.display().to_string(),
+            error: e,
+        };
+        let mut config: FlokiConfig = match profile {
+            Some(profile) => {
+                let mut value: serde_yaml::Value =
+                    serde_yaml::from_reader(f).map_err(parse_error)?;
+                apply_profile(&mut value, profile)?;
+                serde_yaml::from_value(value).map_err(parse_error)?
             }
-        })?;
+            None => serde_yaml::from_reader(f).map_err(parse_error)?,
+        };
 
         // Ensure the path to an external yaml file is correct.
         // If the image.yaml.path file is relative, then it should
@@ -141,6 +356,16 @@ impl FlokiConfig {
     }
 }
 
+fn canonical_config_path(file: &path::Path) -> Result<path::PathBuf, Error> {
+    std::fs::canonicalize(file).map_err(|e| {
+        errors::FlokiError::ProblemOpeningConfigYaml {
+            name: file.display().to_string(),
+            error: e,
+        }
+        .into()
+    })
+}
+
 fn default_shell() -> Shell {
     Shell::Shell("sh".into())
 }
@@ -149,6 +374,47 @@ fn default_mount() -> path::PathBuf {
     path::Path::new("/src").to_path_buf()
 }
 
+fn default_platform_check() -> PlatformCheck {
+    PlatformCheck::Warn
+}
+
+/// Parse a duration such as `30m`, `12h` or `7d`. A bare number is in
+/// seconds.
+fn parse_duration(duration: &str) -> Option<Duration> {
+    let duration = duration.trim();
+    let digits = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (number, unit) = duration.split_at(digits);
+    let number: u64 = number.parse().ok()?;
+    let multiplier = match unit.trim_start() {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(multiplier)?))
+}
+
+fn deserialize_refresh_tag_after<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let duration: Option<String> = Option::deserialize(deserializer)?;
+    duration
+        .map(|duration| {
+            parse_duration(&duration).ok_or_else(|| {
+                serde::de::Error::custom(errors::FlokiUserError::InvalidDuration {
+                    field: "refresh_tag_after".to_string(),
+                    duration,
+                })
+            })
+        })
+        .transpose()
+}
+
 fn default_to_false() -> bool {
     false
 }
@@ -157,10 +423,99 @@ fn default_entrypoint() -> Entrypoint {
     Entrypoint::Suppress { suppress: true }
 }
 
+/// Merge a profile from the configuration's `profiles` over the
+/// configuration itself
+fn apply_profile(config: &mut serde_yaml::Value, name: &str) -> Result<(), Error> {
+    let profiles = config
+        .get("profiles")
+        .and_then(serde_yaml::Value::as_mapping);
+    let profile = profiles.and_then(|p| p.get(&name.into())).cloned();
+    match profile {
+        Some(profile) => {
+            debug!("Applying profile '{}'", name);
+            merge_yaml(config, profile);
+            Ok(())
+        }
+        None => {
+            let available: Vec<&str> = profiles
+                .map(|p| p.iter().filter_map(|(k, _)| k.as_str()).collect())
+                .unwrap_or_default();
+            Err(errors::FlokiUserError::UnknownProfile {
+                name: name.to_string(),
+                available: if available.is_empty() {
+                    "none".into()
+                } else {
+                    available.join(", ")
+                },
+            }
+            .into())
+        }
+    }
+}
+
+/// Deep merge one yaml value over another. Mappings are merged key by key,
+/// and anything else, including lists, is replaced.
+fn merge_yaml(base: &mut serde_yaml::Value, overlay: serde_yaml::Value) {
+    match (base, overlay) {
+        (serde_yaml::Value::Mapping(base), serde_yaml::Value::Mapping(overlay)) => {
+            for (key, value) in overlay {
+                match base.get_mut(&key) {
+                    Some(existing) => merge_yaml(existing, value),
+                    None => {
+                        base.insert(key, value);
+                    }
+                }
+            }
+        }
+        (base, overlay) => *base = overlay,
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
+    #[test]
+    fn test_parse_duration() {
+        let cases = [
+            ("90", 90),
+            ("90s", 90),
+            ("30m", 30 * 60),
+            ("12h", 12 * 60 * 60),
+            ("7d", 7 * 24 * 60 * 60),
+            ("2w", 14 * 24 * 60 * 60),
+        ];
+        for (duration, seconds) in cases.iter() {
+            assert_eq!(
+                parse_duration(duration),
+                Some(Duration::from_secs(*seconds)),
+                "{}",
+                duration
+            );
+        }
+        for bad in &["", "1y", "d", "-1d", "1.5h", "99999999999999999999w"] {
+            assert_eq!(parse_duration(bad), None, "{} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_refresh_tag_after() -> Result<(), Error> {
+        let config: FlokiConfig = serde_yaml::from_str("image: foo\nrefresh_tag_after: 12h")?;
+        assert_eq!(
+            config.refresh_tag_after,
+            Some(Duration::from_secs(12 * 60 * 60))
+        );
+        let config: FlokiConfig = serde_yaml::from_str("image: foo\nrefresh_tag_after: 90")?;
+        assert_eq!(config.refresh_tag_after, Some(Duration::from_secs(90)));
+        let config: FlokiConfig = serde_yaml::from_str("image: foo")?;
+        assert_eq!(config.refresh_tag_after, None);
+        // Mistakes are found when the configuration is read
+        let err =
+            serde_yaml::from_str::<FlokiConfig>("image: foo\nrefresh_tag_after: 1y").unwrap_err();
+        assert!(err.to_string().contains("Invalid refresh_tag_after '1y'"));
+        Ok(())
+    }
+
     #[derive(Debug, PartialEq, Serialize, Deserialize)]
     struct TestShellConfig {
         shell: Shell,
@@ -217,6 +572,16 @@ mod test {
         assert_eq!(actual, expected);
     }
 
+    #[test]
+    fn test_dind_socket_config() {
+        let yaml = "dind: socket";
+        let expected = TestDindConfig {
+            dind: DindConfig::Mode(DindMode::Socket),
+        };
+        let actual: TestDindConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual, expected);
+    }
+
     #[derive(Debug, PartialEq, Serialize, Deserialize)]
     struct TestEntrypointConfig {
         entrypoint: Entrypoint,
@@ -243,4 +608,134 @@ mod test {
         assert_eq!(actual, expected);
         assert_eq!(actual.entrypoint.value(), None);
     }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct TestSecretsConfig {
+        secrets: BTreeMap<String, Secret>,
+    }
+
+    #[test]
+    fn test_secrets_config() {
+        let yaml = "secrets:\n  TOKEN:\n    keyring:\n      service: github\n      user: me\n  npmrc:\n    keyring:\n      service: npm\n      user: me\n    mount: /home/me/.npmrc";
+        let actual: TestSecretsConfig = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual.secrets["TOKEN"].keyring.service, "github");
+        assert_eq!(actual.secrets["TOKEN"].mount, None);
+        assert_eq!(
+            actual.secrets["npmrc"].mount,
+            Some(path::PathBuf::from("/home/me/.npmrc"))
+        );
+    }
+
+    fn config_with_profile(yaml: &str, profile: &str) -> Result<FlokiConfig, Error> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        std::io::Write::write_all(&mut file, yaml.as_bytes())?;
+        FlokiConfig::from_file_with_profile(file.path(), Some(profile))
+    }
+
+    #[test]
+    fn test_profile_is_merged() -> Result<(), Error> {
+        let yaml = "image: debian\nforward_user: true\ndocker_switches: [--privileged]\nenv:\n  MODE: dev\n  KEEP: base\nprofiles:\n  ci:\n    image: debian:slim\n    docker_switches: [--network=none]\n    env:\n      MODE: ci";
+        let config = config_with_profile(yaml, "ci")?;
+
+        assert_eq!(config.image, image::Image::Name("debian:slim".into()));
+        assert!(config.forward_user);
+        // Lists are replaced rather than appended to
+        assert_eq!(config.docker_switches, vec!["--network=none".to_string()]);
+        // Mappings are merged
+        assert_eq!(config.env["MODE"], "ci");
+        assert_eq!(config.env["KEEP"], "base");
+        Ok(())
+    }
+
+    #[test]
+    fn test_use_default_registry() -> Result<(), Error> {
+        let yaml = "image: app\nimage_matrix: [app:1, localhost/app:2]";
+        let mut config: FlokiConfig = serde_yaml::from_str(yaml)?;
+        config.use_default_registry(None);
+        assert_eq!(config.image, image::Image::Name("app".into()));
+
+        config.use_default_registry(Some("env.example.com"));
+        assert_eq!(
+            config.image,
+            image::Image::Name("env.example.com/app".into())
+        );
+        assert_eq!(
+            config.image_matrix,
+            vec!["env.example.com/app:1", "localhost/app:2"]
+        );
+
+        // The configured registry takes precedence
+        let mut config: FlokiConfig =
+            serde_yaml::from_str("image: app\ndefault_registry: config.example.com")?;
+        config.use_default_registry(Some("env.example.com"));
+        assert_eq!(
+            config.image,
+            image::Image::Name("config.example.com/app".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_unknown_profile() -> Result<(), Error> {
+        let yaml = "image: debian\nprofiles:\n  ci: {}\n  test: {}";
+        let err = config_with_profile(yaml, "prod").unwrap_err();
+        match err.downcast_ref::<errors::FlokiUserError>() {
+            Some(errors::FlokiUserError::UnknownProfile { name, available }) => {
+                assert_eq!(name, "prod");
+                assert_eq!(available, "ci, test");
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_image_from() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        for project in &["app", "tools", "base"] {
+            std::fs::create_dir(root.path().join(project))?;
+        }
+        let write = |project: &str, yaml: &str| {
+            std::fs::write(root.path().join(project).join("floki.yaml"), yaml)
+        };
+        write("app", "image: app\nreuse_image_from: ../tools/floki.yaml")?;
+        write(
+            "tools",
+            "image: tools\nreuse_image_from: ../base/floki.yaml",
+        )?;
+        write(
+            "base",
+            "image:\n  build:\n    name: base\n    dockerfile: Dockerfile",
+        )?;
+
+        let config = FlokiConfig::from_file(&root.path().join("app/floki.yaml"))?;
+        assert_eq!(config.image.name()?, "base:floki");
+        // The image is still built, from the base project's directory
+        assert!(matches!(config.image, image::Image::Build { .. }));
+        assert_eq!(
+            config.image_root,
+            Some(root.path().canonicalize()?.join("base"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_image_from_cycle() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(
+            root.path().join("a.yaml"),
+            "image: a\nreuse_image_from: b.yaml",
+        )?;
+        std::fs::write(
+            root.path().join("b.yaml"),
+            "image: b\nreuse_image_from: a.yaml",
+        )?;
+
+        let err = FlokiConfig::from_file(&root.path().join("a.yaml")).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::FlokiUserError>(),
+            Some(errors::FlokiUserError::ReuseImageCycle { .. })
+        ));
+        Ok(())
+    }
 }
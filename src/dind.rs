@@ -2,23 +2,37 @@
 use anyhow::Error;
 use std::path;
 
-use crate::command::{DaemonHandle, DockerCommandBuilder};
+use crate::command::{DaemonHandle, DockerCommandBuilder, DockerOutput};
 use crate::image::{image_exists_locally, pull_image};
 
 pub const DEFAULT_DIND_IMAGE: &str = "docker:dind";
 
+/// The docker socket used when none is configured, and the place
+/// the docker socket is mounted in the container
+pub const DEFAULT_DOCKER_SOCKET: &str = "/var/run/docker.sock";
+
 #[derive(Debug)]
 pub struct Dind {
     command: DockerCommandBuilder,
 }
 
 impl Dind {
-    pub fn new(image: &str, mount: (&path::PathBuf, &path::PathBuf)) -> Self {
-        Dind {
-            command: DockerCommandBuilder::new(image)
-                .add_docker_switch("--privileged")
-                .add_volume(mount),
+    pub fn new(
+        image: &str,
+        mount: (&path::PathBuf, &path::PathBuf),
+        stop_timeout: Option<u64>,
+        restart: Option<&str>,
+    ) -> Self {
+        let mut command = DockerCommandBuilder::new(image)
+            .add_docker_switch("--privileged")
+            .add_volume(mount);
+        if let Some(timeout) = stop_timeout {
+            command = command.set_stop_timeout(timeout);
+        }
+        if let Some(policy) = restart {
+            command = command.set_restart_policy(policy);
         }
+        Dind { command }
     }
 
     pub fn name(&self) -> &str {
@@ -39,10 +53,66 @@ impl Dind {
 }
 
 /// Check the docker dind image is available
-pub fn dind_preflight(image: &str) -> Result<(), Error> {
+pub fn dind_preflight(image: &str, output: DockerOutput) -> Result<(), Error> {
     if image_exists_locally(image)? {
         Ok(())
     } else {
-        pull_image(image)
+        pull_image(image, false, output)
+    }
+}
+
+/// Find the docker socket to forward into the container. An explicitly
+/// configured socket is preferred, then a unix socket named in DOCKER_HOST.
+pub fn resolve_docker_socket(
+    configured: Option<&path::Path>,
+    docker_host: Option<&str>,
+) -> path::PathBuf {
+    configured
+        .map(path::Path::to_path_buf)
+        .or_else(|| docker_host.and_then(socket_from_docker_host))
+        .unwrap_or_else(|| DEFAULT_DOCKER_SOCKET.into())
+}
+
+/// Extract the socket path from a DOCKER_HOST of the form unix:///path
+fn socket_from_docker_host(docker_host: &str) -> Option<path::PathBuf> {
+    docker_host
+        .strip_prefix("unix://")
+        .filter(|p| !p.is_empty())
+        .map(path::PathBuf::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_socket_from_docker_host() {
+        assert_eq!(
+            socket_from_docker_host("unix:///run/user/1000/docker.sock"),
+            Some("/run/user/1000/docker.sock".into())
+        );
+        assert_eq!(socket_from_docker_host("tcp://localhost:2375"), None);
+        assert_eq!(socket_from_docker_host("unix://"), None);
+    }
+
+    #[test]
+    fn test_resolve_docker_socket() {
+        let host = Some("unix:///run/user/1000/docker.sock");
+        assert_eq!(
+            resolve_docker_socket(Some(path::Path::new("/custom.sock")), host),
+            path::PathBuf::from("/custom.sock")
+        );
+        assert_eq!(
+            resolve_docker_socket(None, host),
+            path::PathBuf::from("/run/user/1000/docker.sock")
+        );
+        assert_eq!(
+            resolve_docker_socket(None, Some("tcp://localhost:2375")),
+            path::PathBuf::from(DEFAULT_DOCKER_SOCKET)
+        );
+        assert_eq!(
+            resolve_docker_socket(None, None),
+            path::PathBuf::from(DEFAULT_DOCKER_SOCKET)
+        );
     }
 }
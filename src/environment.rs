@@ -39,26 +39,72 @@ pub struct Environment {
     /// The host folder that floki uses to e.g. create directories
     /// to back volumes
     pub floki_workspace: path::PathBuf,
+    /// The git common directory, if floki_root is in a git worktree
+    pub git_common_dir: Option<path::PathBuf>,
+    /// The value of DOCKER_HOST, if set
+    pub docker_host: Option<String>,
+    /// The value of FLOKI_REGISTRY, if set
+    pub default_registry: Option<String>,
 }
 
 impl Environment {
-    /// Gather information on the environment floki is running in
-    pub fn gather(config_file: &Option<path::PathBuf>) -> Result<Self, Error> {
-        let (floki_root, config_path) = resolve_floki_root_and_config(config_file)?;
+    /// Gather information on the environment floki is running in. If
+    /// `from` is given, floki behaves as if launched in that directory.
+    pub fn gather(
+        config_file: &Option<path::PathBuf>,
+        from: &Option<path::PathBuf>,
+    ) -> Result<Self, Error> {
+        let current_directory = launch_directory(from)?;
+        let (floki_root, config_path) =
+            resolve_floki_root_and_config(config_file, &current_directory)?;
+        let config_file = normalize_path(config_path)?;
+        Ok(Self::for_root(current_directory, floki_root, config_file))
+    }
+
+    /// Gather information on the environment for running without a
+    /// configuration file. The launch directory is used as the floki root,
+    /// and the configuration file is taken to be a `floki.yaml` there,
+    /// which doesn't exist.
+    pub fn gather_without_config(from: &Option<path::PathBuf>) -> Result<Self, Error> {
+        let current_directory = launch_directory(from)?;
+        let config_file = current_directory.join("floki.yaml");
+        Ok(Self::for_root(
+            current_directory.clone(),
+            current_directory,
+            config_file,
+        ))
+    }
+
+    fn for_root(
+        current_directory: path::PathBuf,
+        floki_root: path::PathBuf,
+        config_file: path::PathBuf,
+    ) -> Self {
         let user = User::current();
 
         let env = Environment {
             user_details: user,
-            current_directory: get_current_working_directory()?,
+            current_directory,
+            git_common_dir: find_git_common_dir(&floki_root),
             floki_root,
-            config_file: normalize_path(config_path)?,
+            config_file,
             ssh_agent_socket: get_ssh_agent_socket_path(),
             floki_workspace: get_floki_work_path(user.uid),
+            docker_host: env::var("DOCKER_HOST").ok(),
+            default_registry: env::var("FLOKI_REGISTRY").ok(),
         };
 
         debug!("Got environment {:?}", &env);
 
-        Ok(env)
+        env
+    }
+}
+
+/// The directory floki acts as if it was launched in
+fn launch_directory(from: &Option<path::PathBuf>) -> Result<path::PathBuf, Error> {
+    match from {
+        Some(dir) => normalize_path(dir.clone()),
+        None => get_current_working_directory(),
     }
 }
 
@@ -82,6 +128,29 @@ fn find_floki_yaml(current_directory: &path::Path) -> Result<path::PathBuf, Erro
         .ok_or_else(|| errors::FlokiError::ProblemFindingConfigYaml {}.into())
 }
 
+/// Find the git common directory for a git worktree. In a worktree `.git`
+/// is a file pointing at the worktree's git directory, which in turn
+/// records the common directory shared with the main repository. For a
+/// normal repository (or no repository at all) this returns None.
+fn find_git_common_dir(directory: &path::Path) -> Option<path::PathBuf> {
+    let dot_git = directory
+        .ancestors()
+        .map(|a| a.join(".git"))
+        .find(|f| f.exists())?;
+    if !dot_git.is_file() {
+        return None;
+    }
+
+    let contents = std::fs::read_to_string(&dot_git).ok()?;
+    let gitdir = contents.trim().strip_prefix("gitdir:")?.trim();
+    let gitdir = dot_git.parent()?.join(gitdir);
+    let commondir = std::fs::read_to_string(gitdir.join("commondir")).ok()?;
+    let common_dir = std::fs::canonicalize(gitdir.join(commondir.trim())).ok();
+
+    debug!("Found git worktree with common directory {:?}", common_dir);
+    common_dir
+}
+
 /// Take a file path, and return a tuple consisting of its parent directory and the file path
 fn locate_file_in_parents(path: path::PathBuf) -> Result<(path::PathBuf, path::PathBuf), Error> {
     let dir = path
@@ -98,12 +167,14 @@ fn locate_file_in_parents(path: path::PathBuf) -> Result<(path::PathBuf, path::P
 /// is specified, and we have to search for it.
 fn resolve_floki_root_and_config(
     config_file: &Option<path::PathBuf>,
+    current_directory: &path::Path,
 ) -> Result<(path::PathBuf, path::PathBuf), Error> {
     match config_file {
-        Some(path) => Ok((get_current_working_directory()?, path.clone())),
-        None => Ok(locate_file_in_parents(find_floki_yaml(
-            &get_current_working_directory()?,
-        )?)?),
+        Some(path) => Ok((
+            current_directory.to_path_buf(),
+            current_directory.join(path),
+        )),
+        None => Ok(locate_file_in_parents(find_floki_yaml(current_directory)?)?),
     }
 }
 
@@ -137,7 +208,11 @@ mod test {
             path.parent()
                 .ok_or_else(|| anyhow!("Unable to take parent of path"))?,
         )?;
-        fs::OpenOptions::new().create(true).write(true).open(path)?;
+        fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
         Ok(())
     }
 
@@ -162,6 +237,67 @@ mod test {
         Ok(())
     }
 
+    fn write_file(path: &path::Path, contents: &str) -> Result<(), Error> {
+        touch_file(path)?;
+        fs::write(path, contents)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_git_common_dir_worktree() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let main_git = tmp_dir.path().join("main/.git");
+        let worktree_git = main_git.join("worktrees/feature");
+        write_file(&worktree_git.join("commondir"), "../..\n")?;
+        write_file(
+            &tmp_dir.path().join("feature/.git"),
+            &format!("gitdir: {}\n", worktree_git.display()),
+        )?;
+
+        assert_eq!(
+            find_git_common_dir(&tmp_dir.path().join("feature/subdir")),
+            Some(fs::canonicalize(main_git)?)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_find_git_common_dir_normal_repo() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        fs::create_dir_all(tmp_dir.path().join("main/.git"))?;
+        assert_eq!(find_git_common_dir(&tmp_dir.path().join("main")), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_from_directory() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let project = fs::canonicalize(tmp_dir.path())?;
+        touch_file(&project.join("floki.yaml"))?;
+        fs::create_dir_all(project.join("subdir"))?;
+
+        let env = Environment::gather(&None, &Some(project.join("subdir")))?;
+        assert_eq!(env.current_directory, project.join("subdir"));
+        assert_eq!(env.floki_root, project);
+        assert_eq!(env.config_file, project.join("floki.yaml"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gather_from_directory_with_config() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let project = fs::canonicalize(tmp_dir.path())?;
+        touch_file(&project.join("configs/floki-alt.yaml"))?;
+
+        let env = Environment::gather(
+            &Some("configs/floki-alt.yaml".into()),
+            &Some(project.clone()),
+        )?;
+        assert_eq!(env.floki_root, project);
+        assert_eq!(env.config_file, project.join("configs/floki-alt.yaml"));
+        Ok(())
+    }
+
     #[test]
     fn test_find_floki_yaml_sibling() -> Result<(), Error> {
         let tmp_dir = tempfile::TempDir::new()?;
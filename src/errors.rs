@@ -1,4 +1,5 @@
 /// Error type for floki
+use serde::Serialize;
 use std::fmt;
 use std::io;
 use std::process::ExitStatus;
@@ -28,6 +29,21 @@ pub enum FlokiError {
         error: serde_yaml::Error,
     },
 
+    #[error("There was a problem writing the configuration file '{name}': {error:?}")]
+    ProblemWritingConfigYaml { name: String, error: io::Error },
+
+    #[error("There was a problem reading the command file '{name}': {error:?}")]
+    ProblemReadingCommandFile { name: String, error: io::Error },
+
+    #[error("There was a problem writing the output file '{name}': {error:?}")]
+    ProblemWritingOutputFile { name: String, error: io::Error },
+
+    #[error("There was a problem reading the env_file '{name}': {error:?}")]
+    ProblemReadingEnvFile { name: String, error: io::Error },
+
+    #[error("There was a problem reading the env passthrough file '{name}': {error:?}")]
+    ProblemReadingPassthroughFile { name: String, error: io::Error },
+
     #[error("Running docker command failed with error: {error:?}")]
     FailedToLaunchDocker { error: io::Error },
 
@@ -46,17 +62,57 @@ pub enum FlokiError {
         exit_status: FlokiSubprocessExitStatus,
     },
 
+    #[error("Failed to list docker images: {exit_status}")]
+    FailedToListImages {
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error("Failed to remove docker image '{image}': {exit_status}")]
+    FailedToRemoveImage {
+        image: String,
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error("Failed to inspect docker image '{image}': {exit_status}")]
+    FailedToInspectImage {
+        image: String,
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error("Docker image '{image}' has no registry digest")]
+    FailedToFindImageDigest { image: String },
+
+    #[error("Failed to load docker image archive '{file}': {exit_status}")]
+    FailedToLoadImage {
+        file: String,
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
+    #[error("Loading image archive '{file}' did not provide the image '{image}'")]
+    LoadedImageNotFound { image: String, file: String },
+
     #[error("Failed to check existence of image '{image}': {error:?}")]
     FailedToCheckForImage { image: String, error: io::Error },
 
     #[error("Failed to find the key '{key}' in file '{file}'")]
     FailedToFindYamlKey { key: String, file: String },
 
+    #[error("Failed to run pre_run command '{command}': {error:?}")]
+    FailedToLaunchPreRunCommand { command: String, error: io::Error },
+
+    #[error("Running pre_run command failed: {exit_status}")]
+    PreRunCommandFailed {
+        exit_status: FlokiSubprocessExitStatus,
+    },
+
     #[error("Running container failed: {exit_status:?}")]
     RunContainerFailed {
         exit_status: FlokiSubprocessExitStatus,
     },
 
+    #[error("The command failed in {failed} of {total} images in the matrix")]
+    MatrixRunFailed { failed: usize, total: usize },
+
     #[error("Unable to forward ssh socket - cannot find SSH_AUTH_SOCK in environment - do you have an ssh agent running?")]
     NoSshAuthSock {},
 
@@ -109,4 +165,257 @@ pub enum FlokiInternalError {
 pub enum FlokiUserError {
     #[error("Invalid verbosity setting of {setting:?}. Use a setting between 0 and 3 (-vvv)")]
     InvalidVerbositySetting { setting: u8 },
+
+    #[error("The build for image '{name}' sets both dockerfile and dockerfile_inline. Use one or the other")]
+    ConflictingDockerfiles { name: String },
+
+    #[error("The -l/--local flag has been removed and no longer has any effect. Remove it from your command line")]
+    LocalFlagRemoved {},
+
+    #[error(
+        "Could not find the secret '{name}' in the keyring (service '{service}', user '{user}')"
+    )]
+    SecretNotFound {
+        name: String,
+        service: String,
+        user: String,
+    },
+
+    #[error("Failed to read from the keyring (service '{service}', user '{user}'): {error}")]
+    KeyringLookupFailed {
+        service: String,
+        user: String,
+        error: String,
+    },
+
+    #[error("Invalid stop_timeout of {timeout}. Use a number of seconds from 1 to 3600")]
+    InvalidStopTimeout { timeout: u64 },
+
+    #[error(
+        "Invalid stop_signal '{signal}'. Use a signal name such as SIGQUIT, or a signal number"
+    )]
+    InvalidStopSignal { signal: String },
+
+    #[error(
+        "Invalid restart policy '{policy}'. Use one of: no, on-failure, on-failure:<retries>, always, unless-stopped"
+    )]
+    InvalidRestartPolicy { policy: String },
+
+    #[error("Invalid persist_paths entry '{path}'. Use an absolute path in the container, naming a directory other than /")]
+    InvalidPersistPath { path: String },
+
+    #[error("Invalid prepend_path entry '{path}'. PATH entries can't contain ':'")]
+    InvalidPrependPath { path: String },
+
+    #[error("Invalid exclude '{path}'. Use a relative path within the project")]
+    InvalidExclude { path: String },
+
+    #[error("reuse_image_from refers back to a configuration already in the chain: {chain}")]
+    ReuseImageCycle { chain: String },
+
+    #[error("'{file}' already exists. Pass --force to replace it")]
+    ConfigAlreadyExists { file: String },
+
+    #[error("log_opts are set, but no log_driver is. Set the log_driver the options are for")]
+    LogOptsWithoutDriver {},
+
+    #[error("Invalid {field} '{size}'. Use a size such as 512m or 2g")]
+    InvalidSize { field: String, size: String },
+
+    #[error("Invalid {field} '{duration}'. Use a duration such as 12h or 7d")]
+    InvalidDuration { field: String, duration: String },
+
+    #[error("Invalid umask '{umask}'. Use an octal mode such as 022")]
+    InvalidUmask { umask: String },
+
+    #[error("Could not find the docker socket '{path}' to forward into the container. Set docker_socket to the path of the docker socket")]
+    DockerSocketNotFound { path: String },
+
+    #[error("Malformed item in add_hosts: '{entry}'. Use the form name:ip")]
+    MalformedAddHost { entry: String },
+
+    #[error("Could not find the image archive '{file}'")]
+    ImageArchiveNotFound { file: String },
+
+    #[error("The source '{path}' for volume '{name}' does not exist. Create it, or mark the volume optional")]
+    VolumeSourceNotFound { name: String, path: String },
+
+    #[error("The volume '{name}' sets both source and overlay. Use one or the other")]
+    ConflictingVolumeSources { name: String },
+
+    #[error("The overlay volume '{name}' uses the path '{path}'. Overlay paths can't contain ',', ':' or '\"'")]
+    InvalidOverlayPath { name: String, path: String },
+
+    #[error("Malformed line in env_file '{file}': '{line}'. Use the form KEY=VALUE")]
+    MalformedEnvFile { file: String, line: String },
+
+    #[error("Unknown cache '{name}'. Supported caches: {supported}")]
+    UnknownCache { name: String, supported: String },
+
+    #[error("No profile named '{name}'. Available profiles: {available}")]
+    UnknownProfile { name: String, available: String },
+
+    #[error("Unknown placeholder '{{{{{name}}}}}' in command. Available variables: {available}")]
+    UnknownTemplateVariable { name: String, available: String },
+
+    #[error(
+        "--matrix was given, but image_matrix is empty. List the images to run in image_matrix"
+    )]
+    EmptyImageMatrix {},
+
+    #[error("{feature} needs docker {required} or newer, but docker {detected} was found. Upgrade docker, or pass --skip-version-check")]
+    DockerTooOld {
+        feature: String,
+        required: String,
+        detected: String,
+    },
+
+    #[error("floki is already running in a floki container, which has no docker daemon. Enable dind, or set dind: socket to forward the host's docker socket")]
+    NestedFlokiWithoutDind {},
+
+    #[error("Missing host tools required by requires_host_tools: {tools}. Install them, or add them to PATH")]
+    MissingHostTool { tools: String },
+
+    #[error(
+        "Invalid requires_host_tools entry '{tool}'. Give the name of a program to find on PATH"
+    )]
+    InvalidHostTool { tool: String },
+
+    #[error("Image {image} is built for {architecture}, not {expected}. Set platform_check to warn to run it anyway")]
+    PlatformMismatch {
+        image: String,
+        architecture: String,
+        expected: String,
+    },
+}
+
+/// The exit code floki uses when it fails
+pub const FAILURE_EXIT_CODE: i32 = 1;
+
+/// Broad categories of floki errors, for tools driving floki
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ErrorKind {
+    /// A problem with the floki configuration
+    Config,
+    /// A problem running docker
+    Docker,
+    /// A problem with how floki was used
+    User,
+    /// A bug in floki
+    Internal,
+    /// Anything else
+    Other,
+}
+
+/// A structured description of an error
+#[derive(Debug, PartialEq, Serialize)]
+pub struct ErrorReport {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub exit_code: i32,
+    pub hint: Option<String>,
+}
+
+impl ErrorReport {
+    pub fn from_error(error: &anyhow::Error) -> Self {
+        ErrorReport {
+            kind: error_kind(error),
+            message: error.to_string(),
+            exit_code: FAILURE_EXIT_CODE,
+            hint: error_hint(error).map(str::to_string),
+        }
+    }
+}
+
+/// Categorize an error
+fn error_kind(error: &anyhow::Error) -> ErrorKind {
+    if let Some(e) = error.downcast_ref::<FlokiError>() {
+        match e {
+            FlokiError::ProblemFindingConfigYaml {}
+            | FlokiError::ProblemNormalizingFilePath { .. }
+            | FlokiError::ProblemOpeningConfigYaml { .. }
+            | FlokiError::ProblemParsingConfigYaml { .. }
+            | FlokiError::ProblemWritingConfigYaml { .. }
+            | FlokiError::FailedToFindYamlKey { .. }
+            | FlokiError::ProblemReadingEnvFile { .. }
+            | FlokiError::ProblemReadingPassthroughFile { .. }
+            | FlokiError::MalformedDockerSwitch { .. } => ErrorKind::Config,
+            FlokiError::FailedToLaunchDocker { .. }
+            | FlokiError::FailedToCompleteDockerCommand { .. }
+            | FlokiError::FailedToPullImage { .. }
+            | FlokiError::FailedToBuildImage { .. }
+            | FlokiError::FailedToListImages { .. }
+            | FlokiError::FailedToRemoveImage { .. }
+            | FlokiError::FailedToInspectImage { .. }
+            | FlokiError::FailedToFindImageDigest { .. }
+            | FlokiError::FailedToLoadImage { .. }
+            | FlokiError::LoadedImageNotFound { .. }
+            | FlokiError::FailedToCheckForImage { .. }
+            | FlokiError::RunContainerFailed { .. }
+            | FlokiError::MatrixRunFailed { .. } => ErrorKind::Docker,
+            FlokiError::ProblemReadingCommandFile { .. }
+            | FlokiError::ProblemWritingOutputFile { .. }
+            | FlokiError::FailedToLaunchPreRunCommand { .. }
+            | FlokiError::PreRunCommandFailed { .. }
+            | FlokiError::NoSshAuthSock {} => ErrorKind::User,
+        }
+    } else if error.downcast_ref::<FlokiUserError>().is_some() {
+        ErrorKind::User
+    } else if error.downcast_ref::<FlokiInternalError>().is_some() {
+        ErrorKind::Internal
+    } else {
+        ErrorKind::Other
+    }
+}
+
+/// Suggest what to do about an error, where there is something to suggest
+fn error_hint(error: &anyhow::Error) -> Option<&'static str> {
+    match error.downcast_ref::<FlokiError>()? {
+        FlokiError::ProblemFindingConfigYaml {} => {
+            Some("Run floki from a directory containing floki.yaml, or use --config")
+        }
+        FlokiError::ProblemParsingConfigYaml { .. } => {
+            Some("Check the configuration file against the floki documentation")
+        }
+        FlokiError::FailedToLaunchDocker { .. } => {
+            Some("Check docker is installed and on your PATH")
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_report_json() {
+        let error: anyhow::Error = FlokiError::ProblemFindingConfigYaml {}.into();
+        let json = serde_json::to_value(ErrorReport::from_error(&error)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "kind": "config",
+                "message": "No floki.yaml found in tree",
+                "exit_code": 1,
+                "hint": "Run floki from a directory containing floki.yaml, or use --config",
+            })
+        );
+    }
+
+    #[test]
+    fn test_error_kinds() {
+        let user: anyhow::Error = FlokiUserError::InvalidVerbositySetting { setting: 4 }.into();
+        assert_eq!(error_kind(&user), ErrorKind::User);
+        assert_eq!(ErrorReport::from_error(&user).hint, None);
+
+        let internal: anyhow::Error = FlokiInternalError::InternalAssertionFailed {
+            description: "oops".into(),
+        }
+        .into();
+        assert_eq!(error_kind(&internal), ErrorKind::Internal);
+
+        assert_eq!(error_kind(&anyhow::anyhow!("other")), ErrorKind::Other);
+    }
 }
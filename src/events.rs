@@ -0,0 +1,40 @@
+//! Progress events, for tools which drive floki
+
+/// The phases floki goes through to run a container
+#[derive(Debug, Clone, PartialEq)]
+pub enum Phase {
+    /// Loading and resolving the configuration
+    ResolvingConfig,
+    /// Running the `pre_run` commands on the host
+    RunningPreRun,
+    /// Pulling, building or loading the image
+    ObtainingImage { image: String },
+    /// Starting the floki container
+    Launching { image: String },
+    /// The floki container has exited
+    Exited,
+    /// A dry run resolved the configuration, and stopped before running
+    /// anything
+    DryRun,
+}
+
+/// Something told about floki's progress
+pub trait EventHandler {
+    fn phase(&self, phase: &Phase);
+}
+
+/// Ignores all events
+pub struct NoEvents;
+
+impl EventHandler for NoEvents {
+    fn phase(&self, _phase: &Phase) {}
+}
+
+/// Logs each event at debug level
+pub struct LogEvents;
+
+impl EventHandler for LogEvents {
+    fn phase(&self, phase: &Phase) {
+        debug!("Entering phase: {:?}", phase);
+    }
+}
@@ -0,0 +1,182 @@
+/// Persistence of the interactive shell's history across floki sessions
+use crate::volumes::hash_path;
+use std::path;
+
+static HISTORY_DIRECTORY: &str = "history/";
+
+/// Where the history directory is mounted inside the container
+pub(crate) static HISTORY_MOUNT: &str = "/floki-history";
+
+/// Configuration needed to point a shell at a persisted history file
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ShellHistory {
+    /// Name of the shell's history file, within the history directory
+    pub(crate) file: &'static str,
+    /// How the shell finds the history file
+    pub(crate) location: HistoryLocation,
+}
+
+/// How a shell is pointed at its history in the history mount
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum HistoryLocation {
+    /// An environment variable naming the history file
+    Variable { name: &'static str, value: String },
+    /// A directory in the user's home where the shell keeps its history,
+    /// which is linked to the history mount before the shell starts
+    HomeDirectory { path: &'static str, target: String },
+}
+
+impl ShellHistory {
+    /// A command linking the shell's history directory into the history
+    /// mount, if the shell needs one. A directory the image already has is
+    /// left alone, and a failure to link never stops the container starting.
+    pub(crate) fn link_command(&self) -> Option<String> {
+        match &self.location {
+            HistoryLocation::Variable { .. } => None,
+            HistoryLocation::HomeDirectory { path, target } => {
+                let link = format!("\"$HOME/{}\"", path);
+                let parent = path::Path::new(path).parent()?.display().to_string();
+                Some(format!(
+                    "{{ [ -e {link} ] || {{ mkdir -p \"$HOME/{parent}\" && ln -s {target} {link}; }}; }} 2>/dev/null || true",
+                    link = link,
+                    parent = parent,
+                    target = shlex::quote(target)
+                ))
+            }
+        }
+    }
+}
+
+/// A per-project history directory, and the configuration for the shell
+#[derive(Debug, Clone)]
+pub(crate) struct History {
+    /// The host directory holding history
+    pub(crate) directory: path::PathBuf,
+    /// The shell's history configuration
+    pub(crate) shell: ShellHistory,
+}
+
+impl History {
+    /// Path to the history file on the host
+    pub(crate) fn host_file(&self) -> path::PathBuf {
+        self.directory.join(self.shell.file)
+    }
+}
+
+/// Work out how to persist history for a shell, given as a command such as
+/// `bash` or `/bin/zsh -l`. Returns None for shells we don't know about.
+pub(crate) fn shell_history(shell: &str) -> Option<ShellHistory> {
+    let program = shell.split_whitespace().next()?;
+    let name = path::Path::new(program).file_name()?.to_str()?;
+    // The whole directory is mounted rather than a single file, because
+    // shells may replace their history file by renaming over it
+    match name {
+        "bash" | "sh" | "ash" => Some(ShellHistory {
+            file: "bash_history",
+            location: HistoryLocation::Variable {
+                name: "HISTFILE",
+                value: format!("{}/bash_history", HISTORY_MOUNT),
+            },
+        }),
+        "zsh" => Some(ShellHistory {
+            file: "zsh_history",
+            location: HistoryLocation::Variable {
+                name: "HISTFILE",
+                value: format!("{}/zsh_history", HISTORY_MOUNT),
+            },
+        }),
+        // fish has no setting for where its history is kept, which is
+        // always in ~/.local/share/fish (or under XDG_DATA_HOME)
+        "fish" => Some(ShellHistory {
+            file: "fish/fish_history",
+            location: HistoryLocation::HomeDirectory {
+                path: ".local/share/fish",
+                target: format!("{}/fish", HISTORY_MOUNT),
+            },
+        }),
+        _ => None,
+    }
+}
+
+/// Resolve the history directory for a project
+pub(crate) fn history_directory(
+    work_path: &path::Path,
+    config_filepath: &path::Path,
+) -> path::PathBuf {
+    work_path
+        .join(HISTORY_DIRECTORY)
+        .join(hash_path(config_filepath))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shell_history() {
+        let histfile = |value: &str| HistoryLocation::Variable {
+            name: "HISTFILE",
+            value: value.to_string(),
+        };
+        let bash = shell_history("bash").unwrap();
+        assert_eq!(bash.location, histfile("/floki-history/bash_history"));
+        assert_eq!(bash.link_command(), None);
+        assert_eq!(shell_history("/bin/bash -l"), Some(bash));
+
+        let zsh = shell_history("/usr/bin/zsh").unwrap();
+        assert_eq!(zsh.location, histfile("/floki-history/zsh_history"));
+
+        let fish = shell_history("fish").unwrap();
+        assert_eq!(fish.file, "fish/fish_history");
+        assert_eq!(
+            fish.location,
+            HistoryLocation::HomeDirectory {
+                path: ".local/share/fish",
+                target: "/floki-history/fish".to_string(),
+            }
+        );
+
+        assert_eq!(shell_history("python"), None);
+        assert_eq!(shell_history(""), None);
+    }
+
+    #[test]
+    fn test_fish_link_command() -> Result<(), std::io::Error> {
+        let home = tempfile::tempdir()?;
+        let history = tempfile::tempdir()?;
+        std::fs::create_dir(history.path().join("fish"))?;
+        let fish = ShellHistory {
+            file: "fish/fish_history",
+            location: HistoryLocation::HomeDirectory {
+                path: ".local/share/fish",
+                target: history.path().join("fish").display().to_string(),
+            },
+        };
+
+        let link = |home: &path::Path| {
+            std::process::Command::new("sh")
+                .args(["-c", &fish.link_command().unwrap()])
+                .env("HOME", home)
+                .status()
+        };
+        assert!(link(home.path())?.success());
+        let linked = home.path().join(".local/share/fish");
+        assert_eq!(std::fs::read_link(&linked)?, history.path().join("fish"));
+        // Only fish's data is redirected
+        assert!(!home.path().join(".local/share/other").exists());
+
+        // Running it again, or in a home which can't be written, succeeds
+        assert!(link(home.path())?.success());
+        assert!(link(path::Path::new("/proc/no-such-home"))?.success());
+        Ok(())
+    }
+
+    #[test]
+    fn test_history_directory_is_per_project() {
+        let work_path = path::Path::new("/work");
+        assert_ne!(
+            history_directory(work_path, path::Path::new("/project/1/floki.yaml")),
+            history_directory(work_path, path::Path::new("/project/2/floki.yaml"))
+        );
+    }
+}
@@ -1,35 +1,77 @@
 use anyhow::Error;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::OsStr;
 use std::fs;
+use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime};
 use yaml_rust::YamlLoader;
 
-use crate::errors::{FlokiError, FlokiSubprocessExitStatus};
+use crate::command::{run_with_docker_output, DockerOutput};
+use crate::config::PlatformCheck;
+use crate::errors::{FlokiError, FlokiSubprocessExitStatus, FlokiUserError};
+use crate::telemetry;
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// The tag given to images built by floki
+pub const FLOKI_BUILD_TAG: &str = ":floki";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct BuildSpec {
     name: String,
-    #[serde(default = "default_dockerfile")]
-    dockerfile: PathBuf,
+    dockerfile: Option<PathBuf>,
+    dockerfile_inline: Option<String>,
     #[serde(default = "default_context")]
     context: PathBuf,
     target: Option<String>,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+/// Where the Dockerfile for a build comes from
+#[derive(Debug, PartialEq)]
+enum DockerfileSource<'a> {
+    /// A Dockerfile in the source tree
+    Path(PathBuf),
+    /// Dockerfile content given directly in the configuration
+    Inline(&'a str),
+}
+
+impl BuildSpec {
+    /// Work out where the Dockerfile for this build comes from
+    fn dockerfile_source(&self) -> Result<DockerfileSource<'_>, Error> {
+        match (&self.dockerfile, &self.dockerfile_inline) {
+            (Some(_), Some(_)) => Err(FlokiUserError::ConflictingDockerfiles {
+                name: self.name.clone(),
+            }
+            .into()),
+            (None, Some(inline)) => Ok(DockerfileSource::Inline(inline)),
+            (Some(path), None) => Ok(DockerfileSource::Path(path.clone())),
+            (None, None) => Ok(DockerfileSource::Path(default_dockerfile())),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct YamlSpec {
     pub file: PathBuf,
     key: String,
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ExecSpec {
     command: String,
     args: Vec<String>,
     image: String,
 }
 
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LoadSpec {
+    file: PathBuf,
+    image: String,
+}
+
 fn default_dockerfile() -> PathBuf {
     "Dockerfile".into()
 }
@@ -38,13 +80,14 @@ fn default_context() -> PathBuf {
     ".".into()
 }
 
-#[derive(Debug, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(untagged)]
 pub enum Image {
     Name(String),
     Build { build: BuildSpec },
     Yaml { yaml: YamlSpec },
     Exec { exec: ExecSpec },
+    Load { load: LoadSpec },
 }
 
 impl Image {
@@ -52,7 +95,7 @@ impl Image {
     pub fn name(&self) -> Result<String, Error> {
         match *self {
             Image::Name(ref s) => Ok(s.clone()),
-            Image::Build { ref build } => Ok(build.name.clone() + ":floki"),
+            Image::Build { ref build } => Ok(build.name.clone() + FLOKI_BUILD_TAG),
             Image::Yaml { ref yaml } => {
                 let contents = fs::read_to_string(&yaml.file)?;
                 let raw = YamlLoader::load_from_str(&contents)?;
@@ -78,32 +121,68 @@ impl Image {
                     })
             }
             Image::Exec { ref exec } => Ok(exec.image.clone()),
+            Image::Load { ref load } => Ok(load.image.clone()),
+        }
+    }
+
+    /// Resolve an image given by name against a default registry. Other
+    /// kinds of image are left as they are.
+    pub fn use_default_registry(&mut self, registry: &str) {
+        if let Image::Name(ref mut name) = *self {
+            *name = qualify_image_name(name, registry);
         }
     }
 
     /// Do the required work to get the image, and then return
-    /// it's name
-    pub fn obtain_image(&self, floki_root: &Path) -> Result<String, Error> {
+    /// it's name. `output` says what to do with the output of `docker
+    /// build`.
+    pub fn obtain_image(&self, floki_root: &Path, output: DockerOutput) -> Result<String, Error> {
+        match *self {
+            Image::Build { .. } | Image::Exec { .. } => {
+                telemetry::in_span("build", || self.prepare_image(floki_root, output))
+            }
+            Image::Load { .. } => {
+                telemetry::in_span("load", || self.prepare_image(floki_root, output))
+            }
+            _ => self.prepare_image(floki_root, output),
+        }
+    }
+
+    fn prepare_image(&self, floki_root: &Path, output: DockerOutput) -> Result<String, Error> {
         match *self {
             // Deal with the case where want to build an image
             Image::Build { ref build } => {
+                // Inline Dockerfiles are written to a temporary file, which
+                // is held until the build completes and removed on drop
+                let mut inline_dockerfile = None;
+                let dockerfile = match build.dockerfile_source()? {
+                    DockerfileSource::Path(path) => floki_root.join(path),
+                    DockerfileSource::Inline(content) => {
+                        let mut file = tempfile::NamedTempFile::new()?;
+                        file.write_all(content.as_bytes())?;
+                        file.flush()?;
+                        inline_dockerfile.insert(file).path().to_path_buf()
+                    }
+                };
+
                 let mut command = Command::new("docker");
                 command
                     .arg("build")
                     .arg("-t")
                     .arg(self.name()?)
                     .arg("-f")
-                    .arg(&floki_root.join(&build.dockerfile));
+                    .arg(dockerfile);
 
                 if let Some(target) = &build.target {
                     command.arg("--target").arg(target);
                 }
 
-                let exit_status = command
-                    .arg(&floki_root.join(&build.context))
-                    .spawn()?
-                    .wait()?;
+                info!("Building image: {}", self.name()?);
+                let exit_status =
+                    run_with_docker_output(command.arg(floki_root.join(&build.context)), output)?;
                 if exit_status.success() {
+                    info!("Built image: {}", self.name()?);
+                    forget_image(&self.name()?);
                     Ok(self.name()?)
                 } else {
                     Err(FlokiError::FailedToBuildImage {
@@ -117,12 +196,14 @@ impl Image {
                 }
             }
             Image::Exec { ref exec } => {
-                let exit_status = Command::new(&exec.command)
-                    .args(&exec.args)
-                    .spawn()?
-                    .wait()?;
+                info!("Building image with '{}': {}", exec.command, self.name()?);
+                // The user's own build command reports its progress as it
+                // chooses, so its output is always shown
+                let exit_status = Command::new(&exec.command).args(&exec.args).status()?;
 
                 if exit_status.success() {
+                    info!("Built image: {}", self.name()?);
+                    forget_image(&self.name()?);
                     Ok(self.name()?)
                 } else {
                     Err(FlokiError::FailedToBuildImage {
@@ -135,6 +216,26 @@ impl Image {
                     .into())
                 }
             }
+            Image::Load { ref load } => {
+                let file = floki_root.join(&load.file);
+                if !file.is_file() {
+                    return Err(FlokiUserError::ImageArchiveNotFound {
+                        file: file.display().to_string(),
+                    }
+                    .into());
+                }
+                let loaded = load_image(&file)?;
+                if loaded.contains(&load.image) {
+                    forget_image(&load.image);
+                    Ok(self.name()?)
+                } else {
+                    Err(FlokiError::LoadedImageNotFound {
+                        image: load.image.clone(),
+                        file: file.display().to_string(),
+                    }
+                    .into())
+                }
+            }
             // All other cases we just return the name
             _ => Ok(self.name()?),
         }
@@ -143,16 +244,26 @@ impl Image {
 
 // Now we have some functions which are useful in general
 
-/// Wrapper to pull an image by it's name
-pub fn pull_image(name: &str) -> Result<(), Error> {
-    debug!("Pulling image: {}", name);
-    let exit_status = Command::new("docker")
-        .arg("pull")
-        .arg(name)
-        .spawn()?
-        .wait()?;
+/// Wrapper to pull an image by it's name. If `quiet` is set, docker's
+/// progress output is always suppressed, otherwise it is handled
+/// according to `output`.
+pub fn pull_image(name: &str, quiet: bool, output: DockerOutput) -> Result<(), Error> {
+    telemetry::in_span("pull", || pull(name, quiet, output))
+}
+
+fn pull(name: &str, quiet: bool, output: DockerOutput) -> Result<(), Error> {
+    info!("Pulling image: {}", name);
+    let mut command = Command::new("docker");
+    command.arg("pull").arg(name);
+    let exit_status = if quiet {
+        command.stdout(Stdio::null()).status()?
+    } else {
+        run_with_docker_output(&mut command, output)?
+    };
 
     if exit_status.success() {
+        info!("Pulled image: {}", name);
+        forget_image(name);
         Ok(())
     } else {
         Err(FlokiError::FailedToPullImage {
@@ -166,11 +277,373 @@ pub fn pull_image(name: &str) -> Result<(), Error> {
     }
 }
 
+/// Pull a tagged image again if the local copy is older than `threshold`,
+/// going by when floki last pulled it. Images which aren't available locally
+/// are left for docker to pull, and those floki has no record of pulling are
+/// pulled so their age is known from then on.
+pub(crate) fn refresh_stale_tag(
+    name: &str,
+    threshold: Duration,
+    records: &PullRecords,
+    output: DockerOutput,
+) -> Result<(), Error> {
+    if is_pinned(name) || !image_exists_locally(name)? {
+        return Ok(());
+    }
+    let id = inspect_image(name)?.id;
+    match local_age(records, name, &id, SystemTime::now()) {
+        Some(age) if !is_stale(name, age, threshold) => return Ok(()),
+        Some(age) => info!(
+            "Image {} was pulled {}s ago, longer than refresh_tag_after - pulling it again",
+            name,
+            age.as_secs()
+        ),
+        None => info!(
+            "floki hasn't pulled image {} itself, so can't tell its age - pulling it again",
+            name
+        ),
+    }
+    pull_image(name, false, output)?;
+    records.record(name, &inspect_image(name)?.id)
+}
+
+/// How long before `now` floki pulled the local image `id` for `name`, or
+/// None if it has no record of doing so
+fn local_age(records: &PullRecords, name: &str, id: &str, now: SystemTime) -> Option<Duration> {
+    let pulled = records.pulled_at(name, id)?;
+    Some(now.duration_since(pulled).unwrap_or_default())
+}
+
+/// When floki pulled each tag, kept in the floki workspace. A tag's record
+/// holds the id of the image pulled, and is written at the time of the pull.
+pub(crate) struct PullRecords {
+    directory: PathBuf,
+}
+
+impl PullRecords {
+    pub(crate) fn new(workspace: &Path) -> Self {
+        PullRecords {
+            directory: workspace.join("pulls"),
+        }
+    }
+
+    fn file(&self, name: &str) -> PathBuf {
+        let mut hasher = Sha256::new();
+        hasher.update(name.as_bytes());
+        self.directory.join(format!("{:x}", hasher.finalize()))
+    }
+
+    /// Note that `name` has just been pulled, as the image `id`
+    pub(crate) fn record(&self, name: &str, id: &str) -> Result<(), Error> {
+        fs::create_dir_all(&self.directory)?;
+        fs::write(self.file(name), id)?;
+        Ok(())
+    }
+
+    /// When `name` was last pulled, if the local image is still the one
+    /// pulled then
+    fn pulled_at(&self, name: &str, id: &str) -> Option<SystemTime> {
+        let file = self.file(name);
+        if fs::read_to_string(&file).ok()? != id {
+            return None;
+        }
+        fs::metadata(&file).ok()?.modified().ok()
+    }
+}
+
+/// Whether an image is named by digest, so can never change
+fn is_pinned(name: &str) -> bool {
+    name.contains('@')
+}
+
+/// Whether a local image of the given age should be pulled again
+fn is_stale(name: &str, age: Duration, threshold: Duration) -> bool {
+    !is_pinned(name) && age > threshold
+}
+
+/// Arguments to docker to load an image archive
+fn load_command_args(file: &Path) -> Vec<&OsStr> {
+    vec!["load".as_ref(), "-i".as_ref(), file.as_os_str()]
+}
+
+/// Load images from an archive, returning the names of the loaded images
+pub fn load_image(file: &Path) -> Result<Vec<String>, Error> {
+    info!("Loading image archive: {}", file.display());
+    let output = Command::new("docker")
+        .args(load_command_args(file))
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+    if !output.status.success() {
+        return Err(FlokiError::FailedToLoadImage {
+            file: file.display().to_string(),
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker load".into(),
+                exit_status: output.status,
+            },
+        }
+        .into());
+    }
+
+    let loaded = parse_loaded_images(&String::from_utf8_lossy(&output.stdout));
+    info!("Loaded images: {:?}", loaded);
+    Ok(loaded)
+}
+
+/// Parse the names of images from the output of `docker load`
+fn parse_loaded_images(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| line.trim().strip_prefix("Loaded image: "))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Metadata about a local image, as reported by `docker image inspect`
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImageMetadata {
+    /// The image id
+    pub id: String,
+    /// The registry digest of the image, if it has one, e.g. `sha256:...`
+    pub digest: Option<String>,
+    /// The architecture the image was built for, e.g. `amd64`
+    pub architecture: String,
+    /// The user the image runs as, or empty for root
+    pub user: String,
+}
+
+/// A way of inspecting local images
+pub trait InspectImage {
+    fn inspect(&self, name: &str) -> Result<ImageMetadata, Error>;
+}
+
+/// Inspects images with `docker image inspect`
+pub struct DockerInspect;
+
+impl InspectImage for DockerInspect {
+    fn inspect(&self, name: &str) -> Result<ImageMetadata, Error> {
+        debug!("Inspecting image: {}", name);
+        let output = Command::new("docker")
+            .args([
+                "image",
+                "inspect",
+                "--format",
+                "{{.Id}}{{println}}{{.Architecture}}{{println}}{{.Config.User}}{{println}}{{range .RepoDigests}}{{println .}}{{end}}",
+                name,
+            ])
+            .stdin(Stdio::null())
+            .stderr(Stdio::inherit())
+            .output()
+            .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+        if !output.status.success() {
+            return Err(FlokiError::FailedToInspectImage {
+                image: name.into(),
+                exit_status: FlokiSubprocessExitStatus {
+                    process_description: "docker image inspect".into(),
+                    exit_status: output.status,
+                },
+            }
+            .into());
+        }
+
+        Ok(parse_inspect_output(
+            name,
+            &String::from_utf8_lossy(&output.stdout),
+        ))
+    }
+}
+
+/// Parse the output of our inspect format: the image id, architecture and
+/// user on a line each, followed by the repo digests one
+/// per line
+fn parse_inspect_output(name: &str, output: &str) -> ImageMetadata {
+    let mut parts = output.splitn(4, '\n');
+    let mut next_line = || parts.next().unwrap_or_default().trim().to_string();
+    let id = next_line();
+    let architecture = next_line();
+    let user = next_line();
+    ImageMetadata {
+        id,
+        digest: parse_repo_digest(name, parts.next().unwrap_or_default()),
+        architecture,
+        user,
+    }
+}
+
+/// The docker name for the architecture of the host
+fn host_architecture() -> &'static str {
+    match std::env::consts::ARCH {
+        "x86_64" => "amd64",
+        "x86" => "386",
+        "aarch64" => "arm64",
+        "powerpc64" => "ppc64le",
+        arch => arch,
+    }
+}
+
+/// Check an image's architecture is the one expected: that of the
+/// configured platform (e.g. `linux/arm64`), or otherwise the host's.
+/// A mismatch is warned about or is an error, according to `check`.
+fn check_architecture(
+    image: &str,
+    architecture: &str,
+    platform: Option<&str>,
+    check: PlatformCheck,
+) -> Result<(), Error> {
+    let expected: &str = match platform.and_then(|p| p.split('/').nth(1)) {
+        Some(architecture) => architecture,
+        None => host_architecture(),
+    };
+    if architecture == expected {
+        return Ok(());
+    }
+    match check {
+        PlatformCheck::Warn => {
+            warn!(
+                "Image {} is built for {}, not {}. It may fail to run",
+                image, architecture, expected
+            );
+            Ok(())
+        }
+        PlatformCheck::Error => Err(FlokiUserError::PlatformMismatch {
+            image: image.to_string(),
+            architecture: architecture.to_string(),
+            expected: expected.to_string(),
+        }
+        .into()),
+    }
+}
+
+/// Check a local image's architecture is the one expected. Images which
+/// aren't available locally are pulled for the right platform by docker.
+pub(crate) fn check_image_platform(
+    image: &str,
+    platform: Option<&str>,
+    check: PlatformCheck,
+) -> Result<(), Error> {
+    if !image_exists_locally(image)? {
+        return Ok(());
+    }
+    let metadata = inspect_image(image)?;
+    check_architecture(image, &metadata.architecture, platform, check)
+}
+
+/// The user an image runs as, which is empty for root. The image is pulled
+/// first if it isn't available locally.
+pub(crate) fn image_user(image: &str, output: DockerOutput) -> Result<String, Error> {
+    if !image_exists_locally(image)? {
+        pull_image(image, false, output)?;
+    }
+    Ok(inspect_image(image)?.user)
+}
+
+/// Remembers image metadata, so each image is inspected at most once
+pub struct InspectCache<I: InspectImage> {
+    backend: I,
+    entries: RefCell<HashMap<String, ImageMetadata>>,
+}
+
+impl<I: InspectImage> InspectCache<I> {
+    pub fn new(backend: I) -> Self {
+        InspectCache {
+            backend,
+            entries: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /// Inspect an image, reusing any earlier result
+    pub fn inspect(&self, name: &str) -> Result<ImageMetadata, Error> {
+        if let Some(metadata) = self.entries.borrow().get(name) {
+            return Ok(metadata.clone());
+        }
+        let metadata = self.backend.inspect(name)?;
+        self.entries
+            .borrow_mut()
+            .insert(name.to_string(), metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Forget what we know about an image, e.g. because it was rebuilt
+    pub fn invalidate(&self, name: &str) {
+        self.entries.borrow_mut().remove(name);
+    }
+}
+
+thread_local! {
+    static INSPECT_CACHE: InspectCache<DockerInspect> = InspectCache::new(DockerInspect);
+}
+
+/// Inspect a local image. Each image is only inspected once per run of
+/// floki, unless floki itself changes it.
+pub fn inspect_image(name: &str) -> Result<ImageMetadata, Error> {
+    INSPECT_CACHE.with(|cache| cache.inspect(name))
+}
+
+/// Drop any cached metadata for an image which floki has changed
+fn forget_image(name: &str) {
+    INSPECT_CACHE.with(|cache| cache.invalidate(name))
+}
+
+/// Find the registry digest of a local image, e.g. `sha256:...`
+pub fn image_digest(name: &str) -> Result<String, Error> {
+    inspect_image(name)?
+        .digest
+        .ok_or_else(|| FlokiError::FailedToFindImageDigest { image: name.into() }.into())
+}
+
+/// Prefix an image name with a registry, unless it names one already. An
+/// image name starts with a registry host if its first path segment
+/// contains a dot or a port, or is `localhost`.
+pub(crate) fn qualify_image_name(name: &str, registry: &str) -> String {
+    let qualified = match name.split_once('/') {
+        Some((first, _)) => first.contains('.') || first.contains(':') || first == "localhost",
+        None => false,
+    };
+    if qualified {
+        name.to_string()
+    } else {
+        format!("{}/{}", registry.trim_end_matches('/'), name)
+    }
+}
+
+/// The repository part of an image name, without any tag or digest
+fn repository(name: &str) -> &str {
+    let name = name.split('@').next().unwrap_or(name);
+    match name.rfind(':') {
+        // A colon before the last slash separates a registry port, not a tag
+        Some(i) if !name[i..].contains('/') => &name[..i],
+        _ => name,
+    }
+}
+
+/// A repository name as docker reports it, without the implied Docker Hub
+/// registry and `library/` namespace
+fn short_repository(repository: &str) -> &str {
+    let repository = repository.strip_prefix("docker.io/").unwrap_or(repository);
+    repository.strip_prefix("library/").unwrap_or(repository)
+}
+
+/// Pick the digest for an image out of its repo digests, one per line in
+/// the form `repository@sha256:...`. Only a digest from the image's own
+/// repository is used, as digests from other repositories name other
+/// images.
+fn parse_repo_digest(name: &str, output: &str) -> Option<String> {
+    let repository = short_repository(repository(name));
+    output
+        .lines()
+        .filter_map(|line| line.trim().split_once('@'))
+        .find(|(repo, _)| short_repository(repo) == repository)
+        .map(|(_, digest)| digest.to_string())
+}
+
 /// Determine whether an image exists locally
 pub fn image_exists_locally(name: &str) -> Result<bool, Error> {
     debug!("Checking for image: {}", name);
     let ret = Command::new("docker")
-        .args(&["history", name])
+        .args(["history", name])
         .stdin(Stdio::null())
         .stdout(Stdio::null())
         .stderr(Stdio::null())
@@ -182,6 +655,91 @@ pub fn image_exists_locally(name: &str) -> Result<bool, Error> {
     Ok(ret.code() == Some(0))
 }
 
+/// A docker image present in the local image store
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalImage {
+    /// Name of the image, in `repository:tag` form
+    pub name: String,
+    /// The image id
+    pub id: String,
+    /// When the image was created, as docker lists it, e.g.
+    /// `2022-01-25 10:00:00 +0000 UTC`
+    pub created: String,
+}
+
+impl LocalImage {
+    /// Was this image built by floki?
+    pub fn is_floki_built(&self) -> bool {
+        self.name.ends_with(FLOKI_BUILD_TAG)
+    }
+}
+
+/// List the images in the local image store
+pub fn list_local_images() -> Result<Vec<LocalImage>, Error> {
+    debug!("Listing local images");
+    let output = Command::new("docker")
+        .args([
+            "images",
+            "--format",
+            "{{.Repository}}:{{.Tag}}\t{{.ID}}\t{{.CreatedAt}}",
+        ])
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| FlokiError::FailedToLaunchDocker { error: e })?;
+
+    if !output.status.success() {
+        return Err(FlokiError::FailedToListImages {
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker images".into(),
+                exit_status: output.status,
+            },
+        }
+        .into());
+    }
+
+    Ok(parse_image_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parse the output of `docker images` into local images
+fn parse_image_list(output: &str) -> Vec<LocalImage> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            Some(LocalImage {
+                name: fields.next()?.to_string(),
+                id: fields.next()?.to_string(),
+                created: fields.next()?.to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Remove an image from the local image store by name
+pub fn remove_image(name: &str) -> Result<(), Error> {
+    debug!("Removing image: {}", name);
+    let exit_status = Command::new("docker")
+        .args(["rmi", name])
+        .stdin(Stdio::null())
+        .spawn()?
+        .wait()?;
+    forget_image(name);
+
+    if exit_status.success() {
+        Ok(())
+    } else {
+        Err(FlokiError::FailedToRemoveImage {
+            image: name.into(),
+            exit_status: FlokiSubprocessExitStatus {
+                process_description: "docker rmi".into(),
+                exit_status,
+            },
+        }
+        .into())
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -208,7 +766,8 @@ mod test {
             image: Image::Build {
                 build: BuildSpec {
                     name: "foo".into(),
-                    dockerfile: "Dockerfile.test".into(),
+                    dockerfile: Some("Dockerfile.test".into()),
+                    dockerfile_inline: None,
                     context: "./context".into(),
                     target: Some("builder".into()),
                 },
@@ -240,4 +799,360 @@ image:
         let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
         assert!(actual == expected);
     }
+
+    #[test]
+    fn test_parse_image_list() {
+        let output = "foo:floki\tabc123\t2022-01-25 10:00:00 +0000 UTC\n\
+                      debian:sid\tdef456\t2022-01-24 09:00:00 +0000 UTC\n";
+        let images = parse_image_list(output);
+        assert_eq!(
+            images,
+            vec![
+                LocalImage {
+                    name: "foo:floki".into(),
+                    id: "abc123".into(),
+                    created: "2022-01-25 10:00:00 +0000 UTC".into(),
+                },
+                LocalImage {
+                    name: "debian:sid".into(),
+                    id: "def456".into(),
+                    created: "2022-01-24 09:00:00 +0000 UTC".into(),
+                },
+            ]
+        );
+        assert!(images[0].is_floki_built());
+        assert!(!images[1].is_floki_built());
+    }
+
+    #[test]
+    fn test_image_spec_by_build_spec_inline() {
+        let yaml = "image:\n  build:\n    name: foo\n    dockerfile_inline: |\n      FROM alpine:latest\n      RUN apk add bash\n";
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        match actual.image {
+            Image::Build { ref build } => assert_eq!(
+                build.dockerfile_source().unwrap(),
+                DockerfileSource::Inline("FROM alpine:latest\nRUN apk add bash\n")
+            ),
+            _ => panic!("expected a build spec"),
+        }
+    }
+
+    #[test]
+    fn test_build_spec_default_dockerfile() {
+        let yaml = "image:\n  build:\n    name: foo";
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        match actual.image {
+            Image::Build { ref build } => assert_eq!(
+                build.dockerfile_source().unwrap(),
+                DockerfileSource::Path("Dockerfile".into())
+            ),
+            _ => panic!("expected a build spec"),
+        }
+    }
+
+    #[test]
+    fn test_build_spec_conflicting_dockerfiles() {
+        let yaml = "image:\n  build:\n    name: foo\n    dockerfile: Dockerfile\n    dockerfile_inline: FROM alpine:latest";
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        match actual.image {
+            Image::Build { ref build } => assert!(build.dockerfile_source().is_err()),
+            _ => panic!("expected a build spec"),
+        }
+    }
+
+    #[test]
+    fn test_qualify_image_name() {
+        let registry = "registry.example.com";
+        // Bare names
+        assert_eq!(
+            qualify_image_name("app:latest", registry),
+            "registry.example.com/app:latest"
+        );
+        assert_eq!(
+            qualify_image_name("debian", "registry.example.com/"),
+            "registry.example.com/debian"
+        );
+        // Library and organisation names
+        assert_eq!(
+            qualify_image_name("library/debian:sid", registry),
+            "registry.example.com/library/debian:sid"
+        );
+        assert_eq!(
+            qualify_image_name("team/app", registry),
+            "registry.example.com/team/app"
+        );
+        // Fully-qualified names
+        for name in &[
+            "docker.io/library/debian",
+            "other.example.com/app:latest",
+            "registry:5000/app",
+            "localhost/app",
+        ] {
+            assert_eq!(qualify_image_name(name, registry), *name);
+        }
+    }
+
+    #[test]
+    fn test_repository() {
+        assert_eq!(repository("debian"), "debian");
+        assert_eq!(repository("debian:sid"), "debian");
+        assert_eq!(
+            repository("registry:5000/foo/bar:1.0"),
+            "registry:5000/foo/bar"
+        );
+        assert_eq!(repository("registry:5000/foo/bar"), "registry:5000/foo/bar");
+        assert_eq!(repository("foo@sha256:abc"), "foo");
+    }
+
+    #[test]
+    fn test_parse_repo_digest() {
+        let output = "mirror.example.com/debian@sha256:aaaa\ndebian@sha256:bbbb\n";
+        assert_eq!(
+            parse_repo_digest("debian:sid", output),
+            Some("sha256:bbbb".into())
+        );
+        assert_eq!(
+            parse_repo_digest("mirror.example.com/debian:sid", output),
+            Some("sha256:aaaa".into())
+        );
+        assert_eq!(
+            parse_repo_digest("docker.io/library/debian:sid", output),
+            Some("sha256:bbbb".into())
+        );
+        // A digest from another repository is for a different image
+        assert_eq!(parse_repo_digest("other:latest", output), None);
+        assert_eq!(parse_repo_digest("debian:sid", ""), None);
+    }
+
+    #[test]
+    fn test_local_age() -> Result<(), Error> {
+        let workspace = tempfile::tempdir()?;
+        let records = PullRecords::new(workspace.path());
+        let day = Duration::from_secs(86400);
+        let now = SystemTime::now();
+
+        // Without a record of the pull, the age is unknown
+        assert_eq!(
+            local_age(&records, "debian:stable", "sha256:1234", now),
+            None
+        );
+
+        // An old image which has just been pulled is fresh...
+        records.record("debian:stable", "sha256:1234")?;
+        let age = local_age(&records, "debian:stable", "sha256:1234", now).unwrap();
+        assert!(!is_stale("debian:stable", age, 7 * day));
+        // ...until the threshold has passed since the pull
+        let later = now + 8 * day;
+        let age = local_age(&records, "debian:stable", "sha256:1234", later).unwrap();
+        assert!(is_stale("debian:stable", age, 7 * day));
+
+        // The record doesn't apply once the tag is pointed at another image
+        assert_eq!(
+            local_age(&records, "debian:stable", "sha256:5678", now),
+            None
+        );
+        // Records are kept per tag
+        assert_eq!(
+            local_age(&records, "debian:testing", "sha256:1234", now),
+            None
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_stale() {
+        let day = Duration::from_secs(86400);
+        assert!(is_stale("debian:stable", 8 * day, 7 * day));
+        assert!(is_stale("debian", 8 * day, 7 * day));
+        assert!(!is_stale("debian:stable", 6 * day, 7 * day));
+        assert!(!is_stale("debian:stable", 7 * day, 7 * day));
+        // Digests always name the same image, however old
+        assert!(!is_stale("debian@sha256:abcd", 100 * day, 7 * day));
+        assert!(!is_stale(
+            "registry.example.com:5000/debian:stable@sha256:abcd",
+            100 * day,
+            7 * day
+        ));
+    }
+
+    #[test]
+    fn test_parse_inspect_output() {
+        let output = "sha256:1234\namd64\nbuilder\ndebian@sha256:bbbb\n";
+        assert_eq!(
+            parse_inspect_output("debian:sid", output),
+            ImageMetadata {
+                id: "sha256:1234".into(),
+                digest: Some("sha256:bbbb".into()),
+                architecture: "amd64".into(),
+                user: "builder".into(),
+            }
+        );
+        // Locally built images have no repo digests, and most run as root
+        let output = "sha256:1234\namd64\n\n";
+        assert_eq!(parse_inspect_output("foo:floki", output).user, "");
+        assert_eq!(parse_inspect_output("foo:floki", output).digest, None);
+    }
+
+    /// An inspect backend which counts how often it is used
+    struct CountingInspect(std::cell::Cell<usize>);
+
+    impl InspectImage for CountingInspect {
+        fn inspect(&self, name: &str) -> Result<ImageMetadata, Error> {
+            self.0.set(self.0.get() + 1);
+            Ok(ImageMetadata {
+                id: format!("id-{}", name),
+                digest: None,
+                architecture: "amd64".into(),
+                user: String::new(),
+            })
+        }
+    }
+
+    #[test]
+    fn test_check_architecture() {
+        let host = host_architecture();
+        let other = if host == "arm64" { "amd64" } else { "arm64" };
+
+        // Matching the host, or a configured platform
+        assert!(check_architecture("img", host, None, PlatformCheck::Error).is_ok());
+        assert!(check_architecture(
+            "img",
+            other,
+            Some(&format!("linux/{}", other)),
+            PlatformCheck::Error
+        )
+        .is_ok());
+
+        // Mismatches warn or error as configured
+        assert!(check_architecture("img", other, None, PlatformCheck::Warn).is_ok());
+        let err = check_architecture("img", other, None, PlatformCheck::Error).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::PlatformMismatch { .. })
+        ));
+        assert!(check_architecture(
+            "img",
+            host,
+            Some(&format!("linux/{}", other)),
+            PlatformCheck::Error
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_inspect_cache() -> Result<(), Error> {
+        let cache = InspectCache::new(CountingInspect(std::cell::Cell::new(0)));
+
+        assert_eq!(cache.inspect("debian:sid")?.id, "id-debian:sid");
+        assert_eq!(cache.inspect("debian:sid")?.id, "id-debian:sid");
+        assert_eq!(cache.backend.0.get(), 1);
+
+        cache.inspect("alpine:latest")?;
+        assert_eq!(cache.backend.0.get(), 2);
+
+        cache.invalidate("debian:sid");
+        cache.inspect("debian:sid")?;
+        assert_eq!(cache.backend.0.get(), 3);
+        Ok(())
+    }
+
+    #[test]
+    fn test_image_spec_by_load_spec() {
+        let yaml = "image:\n  load:\n    file: images/dev.tar\n    image: dev:1.0";
+        let expected = TestImage {
+            image: Image::Load {
+                load: LoadSpec {
+                    file: "images/dev.tar".into(),
+                    image: "dev:1.0".into(),
+                },
+            },
+        };
+        let actual: TestImage = serde_yaml::from_str(yaml).unwrap();
+        assert_eq!(actual, expected);
+        assert_eq!(actual.image.name().unwrap(), "dev:1.0");
+    }
+
+    #[test]
+    fn test_load_command_args() {
+        assert_eq!(
+            load_command_args(Path::new("/images/dev.tar")),
+            vec!["load", "-i", "/images/dev.tar"]
+        );
+    }
+
+    #[test]
+    fn test_parse_loaded_images() {
+        let output =
+            "Loaded image: dev:1.0\nLoaded image ID: sha256:abcd\nLoaded image: dev:latest\n";
+        assert_eq!(parse_loaded_images(output), vec!["dev:1.0", "dev:latest"]);
+    }
+
+    #[test]
+    fn test_load_missing_archive() {
+        let image = Image::Load {
+            load: LoadSpec {
+                file: "no/such/image.tar".into(),
+                image: "dev:1.0".into(),
+            },
+        };
+        let err = image
+            .obtain_image(Path::new("/nonexistent"), DockerOutput::Capture)
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::ImageArchiveNotFound { .. })
+        ));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_image_spans() {
+        use crate::telemetry::spans::{test::InMemoryExporter, Session};
+
+        let exec = |command: &str| Image::Exec {
+            exec: ExecSpec {
+                command: command.into(),
+                args: Vec::new(),
+                image: "foo:floki".into(),
+            },
+        };
+        let load = Image::Load {
+            load: LoadSpec {
+                file: "missing.tar".into(),
+                image: "foo:floki".into(),
+            },
+        };
+        let root = Path::new("/nonexistent");
+        let exporter = InMemoryExporter::default();
+        {
+            let _session = Session::install(Box::new(exporter.clone()));
+            assert!(exec("true")
+                .obtain_image(root, DockerOutput::Capture)
+                .is_ok());
+            assert!(exec("false")
+                .obtain_image(root, DockerOutput::Capture)
+                .is_err());
+            assert!(load.obtain_image(root, DockerOutput::Capture).is_err());
+            // Images given by name are pulled separately, not obtained
+            let name = Image::Name("foo:floki".into());
+            assert!(name.obtain_image(root, DockerOutput::Capture).is_ok());
+            // docker rejects the uppercase name without contacting a registry
+            assert!(pull_image("floki/INVALID", true, DockerOutput::Capture).is_err());
+        }
+
+        let spans = exporter.0.borrow();
+        let outcomes: Vec<(&str, bool)> = spans
+            .iter()
+            .map(|span| (span.name, span.error.is_some()))
+            .collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                ("build", false),
+                ("build", true),
+                ("load", true),
+                ("pull", true)
+            ]
+        );
+    }
 }
@@ -0,0 +1,116 @@
+/// Scaffolding of a starter floki configuration
+use crate::errors::{FlokiError, FlokiUserError};
+use anyhow::Error;
+
+use std::path;
+
+/// The image used in the starter configuration when none is given
+static DEFAULT_IMAGE: &str = "debian:stable-slim";
+
+/// A commented starter configuration using the given image
+fn starter_config(image: &str) -> String {
+    format!(
+        r#"# floki configuration - see https://metaswitch.github.io/floki/ for the
+# full set of options.
+
+# The image to run. This can also be built from a Dockerfile, e.g.
+#   image:
+#     build:
+#       name: my-project
+#       dockerfile: Dockerfile
+image: "{}"
+
+# The shell to start in the container
+shell: sh
+
+# Where the project is mounted in the container
+mount: /src
+
+# Commands to run in the container before the shell starts
+init: []
+
+# Run as your own user, so files created in the container belong to you
+# forward_user: true
+
+# Forward your ssh agent, e.g. to fetch private git dependencies
+# forward_ssh_agent: true
+
+# Run a docker daemon alongside the container
+# dind: true
+
+# Volumes which persist across sessions, e.g. for build caches
+# volumes:
+#   cargo-registry:
+#     mount: /usr/local/cargo/registry
+"#,
+        image
+    )
+}
+
+/// Write a starter configuration file to `directory`, refusing to replace
+/// an existing one unless `force` is set. Returns the path written.
+pub(crate) fn write_starter_config(
+    directory: &path::Path,
+    image: Option<&str>,
+    force: bool,
+) -> Result<path::PathBuf, Error> {
+    let file = directory.join("floki.yaml");
+    if file.exists() && !force {
+        return Err(FlokiUserError::ConfigAlreadyExists {
+            file: file.display().to_string(),
+        }
+        .into());
+    }
+    std::fs::write(&file, starter_config(image.unwrap_or(DEFAULT_IMAGE))).map_err(|e| {
+        FlokiError::ProblemWritingConfigYaml {
+            name: file.display().to_string(),
+            error: e,
+        }
+    })?;
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::FlokiConfig;
+
+    #[test]
+    fn test_starter_config_parses() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let file = write_starter_config(directory.path(), Some("rust:1.70"), false)?;
+
+        let config = FlokiConfig::from_file(&file)?;
+        assert_eq!(config.image.name()?, "rust:1.70");
+        assert_eq!(config.shell.inner_shell(), "sh");
+        assert_eq!(config.mount, path::Path::new("/src"));
+        assert!(config.init.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_default_image() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let file = write_starter_config(directory.path(), None, false)?;
+        assert_eq!(FlokiConfig::from_file(&file)?.image.name()?, DEFAULT_IMAGE);
+        Ok(())
+    }
+
+    #[test]
+    fn test_existing_config_is_kept() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let file = directory.path().join("floki.yaml");
+        std::fs::write(&file, "image: mine")?;
+
+        let err = write_starter_config(directory.path(), None, false).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::ConfigAlreadyExists { .. })
+        ));
+        assert_eq!(std::fs::read_to_string(&file)?, "image: mine");
+
+        write_starter_config(directory.path(), None, true)?;
+        assert_ne!(std::fs::read_to_string(&file)?, "image: mine");
+        Ok(())
+    }
+}
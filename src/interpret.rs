@@ -1,34 +1,145 @@
 use crate::command;
 use crate::command::DockerCommandBuilder;
+use crate::config::Volume;
 use crate::dind::Dind;
+use crate::errors::{FlokiError, FlokiSubprocessExitStatus, FlokiUserError};
+use crate::events::{EventHandler, Phase};
+use crate::history::{History, HistoryLocation, HISTORY_MOUNT};
+use crate::image::{check_image_platform, image_user, refresh_stale_tag, Image, PullRecords};
+use crate::secrets::ResolvedSecret;
 use crate::spec;
-use crate::volumes::resolve_volume_mounts;
+use crate::telemetry;
+use crate::version::{check_docker_version, DockerFeature};
+use crate::volumes::{
+    config_mounts, persisted_path_volumes, resolve_host_mounts, resolve_overlay_volumes,
+    resolve_volume_mounts, OverlayVolume,
+};
 
 use anyhow::Error;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{Read, Write};
 use std::path;
+use std::process::Command;
+use tempfile::NamedTempFile;
+
+/// Set in floki containers, so floki can tell when it's run inside one
+pub(crate) static IN_CONTAINER_VARIABLE: &str = "FLOKI_IN_CONTAINER";
 
 pub(crate) fn run_floki_container(
     spec: &spec::FlokiSpec,
     inner_command: &str,
+    events: &dyn EventHandler,
 ) -> Result<(), Error> {
-    spec.image.obtain_image(&spec.paths.root)?;
+    check_host_tools(&spec.requires_host_tools, on_path)?;
+
+    if spec.check_docker_version {
+        check_docker_version(&docker_features(spec))?;
+    }
 
-    let mut cmd = command::DockerCommandBuilder::new(&spec.image.name()?)
-        .add_volume((&spec.paths.root, &spec.mount));
+    events.phase(&Phase::RunningPreRun);
+    run_pre_run_commands(&spec.pre_run, &spec.paths.root)?;
+
+    let image = spec.image.name()?;
+    events.phase(&Phase::ObtainingImage {
+        image: image.clone(),
+    });
+    if let (Some(threshold), Image::Name(_)) = (spec.refresh_tag_after, &spec.image) {
+        let records = PullRecords::new(&spec.paths.workspace);
+        refresh_stale_tag(&image, threshold, &records, spec.docker_output)?;
+    }
+    spec.image
+        .obtain_image(&spec.image_root, spec.docker_output)?;
+    check_image_platform(&image, spec.platform.as_deref(), spec.platform_check)?;
+
+    let mut cmd =
+        command::DockerCommandBuilder::new(&image).add_volume((&spec.paths.root, &spec.mount));
+
+    if let Some(name) = &spec.container_name {
+        cmd = cmd.set_name(name);
+    }
+
+    if let Some(platform) = &spec.platform {
+        cmd = cmd
+            .add_docker_switch("--platform")
+            .add_docker_switch(platform);
+    }
+
+    cmd = configure_persisted_paths(cmd, &spec.paths.config, &spec.persist_paths);
+
+    cmd = configure_exclude(cmd, &spec.exclude);
 
     let volumes = resolve_volume_mounts(&spec.paths.config, &spec.paths.workspace, &spec.volumes);
     instantiate_volumes(&volumes)?;
 
     cmd = configure_volumes(cmd, &volumes);
+
+    let host_mounts = resolve_host_mounts(&spec.paths.config, &spec.volumes)?;
+    cmd = configure_volumes(cmd, &host_mounts);
+
+    let overlays =
+        resolve_overlay_volumes(&spec.paths.config, &spec.paths.workspace, &spec.volumes);
+    let overlay_supported = cfg!(target_os = "linux");
+    if overlay_supported {
+        instantiate_overlay_volumes(&overlays)?;
+    }
+    cmd = configure_overlay_volumes(cmd, &overlays, overlay_supported);
+
+    let mounted: Vec<&path::PathBuf> = volumes
+        .iter()
+        .chain(host_mounts.iter())
+        .map(|(_, mount)| *mount)
+        .chain(overlays.iter().map(|overlay| overlay.mount))
+        .collect();
+    // Volumes marked chown are given to the container user by the
+    // container's startup, which runs as root to do so
+    let chown_targets = chown_targets(&spec.volumes, &mounted);
+    let chown_user = if chown_targets.is_empty() {
+        None
+    } else if spec.user.forward {
+        container_user(&spec.user, "")
+    } else {
+        container_user(&spec.user, &image_user(&image, spec.docker_output)?)
+    };
+
     cmd = cmd.add_environment("FLOKI_HOST_MOUNTDIR", &spec.paths.root);
     cmd = cmd.add_environment("FLOKI_HOST_UID", spec.user.uid.to_string());
     cmd = cmd.add_environment("FLOKI_HOST_GID", spec.user.gid.to_string());
+    cmd = cmd.add_environment(IN_CONTAINER_VARIABLE, "1");
+    for (name, value) in spec.variables.iter() {
+        cmd = cmd.add_environment(name, value);
+    }
     cmd = cmd.set_working_directory(&spec.paths.internal_working_directory);
 
-    if spec.user.forward {
+    if chown_user.is_some() {
+        cmd = cmd.add_docker_switch("--user").add_docker_switch("0:0");
+    } else if spec.user.forward {
         cmd = cmd
             .add_docker_switch("--user")
-            .add_docker_switch(&format!("{}:{}", spec.user.uid, spec.user.gid));
+            .add_docker_switch(format!("{}:{}", spec.user.uid, spec.user.gid));
+    }
+
+    cmd = configure_dns(cmd, &spec.dns, &spec.add_hosts);
+    cmd = configure_namespaces(cmd, spec.pid.as_deref(), spec.ipc.as_deref());
+    if let Some(size) = &spec.shm_size {
+        cmd = cmd.set_shm_size(size);
+    }
+    cmd = configure_log_driver(cmd, spec.log_driver.as_deref(), &spec.log_opts);
+
+    if let Some(history) = &spec.history {
+        instantiate_history(history)?;
+        cmd = cmd.add_volume((&history.directory, &path::PathBuf::from(HISTORY_MOUNT)));
+        if let HistoryLocation::Variable { name, value } = &history.shell.location {
+            cmd = cmd.add_environment(name, value);
+        }
+    }
+
+    if let Some(git_common_dir) = &spec.git_common_dir {
+        cmd = cmd.add_volume((git_common_dir, git_common_dir));
+    }
+
+    if let Some(mount) = &spec.mount_config {
+        cmd = configure_config_mount(cmd, &spec.paths.config, mount);
     }
 
     if let Some(spec::SshAgent { path }) = &spec.ssh_agent {
@@ -36,7 +147,19 @@ pub(crate) fn run_floki_container(
     }
 
     if let Some(entrypoint) = &spec.entrypoint {
-        cmd = cmd.add_docker_switch(&format!("--entrypoint={}", entrypoint))
+        cmd = cmd.add_docker_switch(format!("--entrypoint={}", entrypoint))
+    }
+
+    // Hold the secret files until the container exits
+    let (configured, _secret_files) = configure_secrets(cmd, &spec.secrets)?;
+    cmd = configured;
+
+    if let Some(timeout) = spec.stop_timeout {
+        cmd = cmd.set_stop_timeout(timeout);
+    }
+
+    if let Some(signal) = &spec.stop_signal {
+        cmd = cmd.set_stop_signal(signal);
     }
 
     for switch in &spec.docker_switches {
@@ -44,17 +167,98 @@ pub(crate) fn run_floki_container(
     }
 
     // Finally configure dind, taking care to hold a handle for the linked dind container
-    let _handle = if let Some(spec::Dind { image }) = &spec.dind {
-        let dind = Dind::new(image, (&spec.paths.root, &spec.mount));
-        cmd = command::enable_docker_in_docker(cmd, &dind)?;
-        crate::dind::dind_preflight(image)?;
-        Some(dind.launch()?)
-    } else {
-        None
+    let _handle = match &spec.dind {
+        Some(spec::Dind::Container { image }) => {
+            let dind = Dind::new(
+                image,
+                (&spec.paths.root, &spec.mount),
+                spec.stop_timeout,
+                spec.restart.as_deref(),
+            );
+            cmd = command::enable_docker_in_docker(cmd, &dind)?;
+            Some(start_dind(image, dind, spec.docker_output)?)
+        }
+        Some(spec::Dind::Socket { path }) => {
+            cmd = command::enable_docker_socket(cmd, path);
+            None
+        }
+        None => None,
     };
 
-    let subshell_command = subshell_command(&spec.init, inner_command);
-    cmd.run(&[spec.shell.outer_shell(), "-c", &subshell_command])
+    let mut init: Vec<String> = path_export(&spec.prepend_path).into_iter().collect();
+    if let Some(history) = &spec.history {
+        init.extend(history.shell.link_command());
+    }
+    init.extend(spec.init.iter().cloned());
+    let subshell_command = subshell_command(spec.umask.as_deref(), &init, inner_command);
+    info!("Launching container: {}", cmd.name());
+    events.phase(&Phase::Launching { image });
+    let subshell_command = match &chown_user {
+        Some(user) => chown_then_run_as(
+            user,
+            &chown_targets,
+            spec.shell.outer_shell(),
+            &subshell_command,
+        ),
+        None => subshell_command,
+    };
+    let command = [spec.shell.outer_shell(), "-c", &subshell_command];
+    let result = run_container(&cmd, &command, spec.output.as_deref());
+    info!("Container exited: {}", cmd.name());
+    events.phase(&Phase::Exited);
+    result
+}
+
+/// Start the dind container, pulling its image first if it isn't available
+fn start_dind(
+    image: &str,
+    dind: Dind,
+    output: command::DockerOutput,
+) -> Result<command::DaemonHandle, Error> {
+    telemetry::in_span("dind-ready", || {
+        crate::dind::dind_preflight(image, output)?;
+        dind.launch()
+    })
+}
+
+/// Run the command in the container, copying its output to `output` if set
+fn run_container(
+    cmd: &DockerCommandBuilder,
+    command: &[&str],
+    output: Option<&path::Path>,
+) -> Result<(), Error> {
+    telemetry::in_span("run", || match output {
+        Some(output) => cmd.run_with_output(command, output),
+        None => cmd.run(command),
+    })
+}
+
+/// The features in use which need a minimum docker version
+fn docker_features(spec: &spec::FlokiSpec) -> Vec<DockerFeature> {
+    let mut features = Vec::new();
+    if spec.platform.is_some() {
+        features.push(DockerFeature::Platform);
+    }
+    if spec
+        .add_hosts
+        .iter()
+        .any(|host| host.ends_with(":host-gateway"))
+    {
+        features.push(DockerFeature::HostGateway);
+    }
+    if spec.stop_timeout.is_some() {
+        features.push(DockerFeature::StopTimeout);
+    }
+    features
+}
+
+/// The inner shell, wrapped by the command prefix if there is one. Init
+/// commands run before the prefix, so only the user's command is wrapped.
+pub(crate) fn prefixed_shell(shell: &str, prefix: Option<&str>) -> String {
+    match prefix {
+        Some(prefix) => format!("{} {}", prefix, shell),
+        None => shell.to_string(),
+    }
 }
 
 pub(crate) fn command_in_shell(shell: &str, command: &[String]) -> String {
@@ -63,6 +267,104 @@ pub(crate) fn command_in_shell(shell: &str, command: &[String]) -> String {
     inner_shell + " -c \"" + &command.join(" ") + "\""
 }
 
+/// The command for an interactive session in the given shell, showing
+/// the message of the day first if there is one
+pub(crate) fn interactive_command(shell: &str, motd: Option<&str>) -> String {
+    match motd {
+        Some(motd) => format!("printf '%s\\n' {} && {}", shlex::quote(motd), shell),
+        None => shell.to_string(),
+    }
+}
+
+/// Run a script in the given shell. The script is quoted as a single
+/// argument to the shell, so no quoting is required of the user.
+pub(crate) fn script_in_shell(shell: &str, script: &str, exit_on_error: bool) -> String {
+    let script = if exit_on_error {
+        format!("set -e\n{}", script)
+    } else {
+        script.to_string()
+    };
+    format!("{} -c {}", shell, shlex::quote(&script))
+}
+
+/// The variables available to command templates: the user's
+/// `template_vars`, and the built-in `project_name` and `image`
+pub(crate) fn template_variables(
+    template_vars: &BTreeMap<String, String>,
+    root: &path::Path,
+    image: &str,
+) -> BTreeMap<String, String> {
+    let mut variables = template_vars.clone();
+    let project_name = root
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_default();
+    variables.insert("project_name".to_string(), project_name);
+    variables.insert("image".to_string(), image.to_string());
+    variables
+}
+
+/// Whether the text between braces names a template variable. Anything
+/// else, such as docker's own `{{.Names}}` format strings, is left as it is.
+fn is_placeholder_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Substitute `{{name}}` placeholders in a command. Unknown placeholders
+/// are an error, while braces which don't enclose a name, and an
+/// unterminated `{{`, are left as they are.
+pub(crate) fn render_template(
+    template: &str,
+    variables: &BTreeMap<String, String>,
+) -> Result<String, Error> {
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        let end = match rest[start..].find("}}") {
+            Some(end) => start + end,
+            None => break,
+        };
+        let name = rest[start + 2..end].trim();
+        if !is_placeholder_name(name) {
+            rendered.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            continue;
+        }
+        let value = variables
+            .get(name)
+            .ok_or_else(|| FlokiUserError::UnknownTemplateVariable {
+                name: name.to_string(),
+                available: variables.keys().cloned().collect::<Vec<_>>().join(", "),
+            })?;
+        rendered.push_str(&rest[..start]);
+        rendered.push_str(value);
+        rest = &rest[end + 2..];
+    }
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+/// Read a script to run from a file, or from `stdin` if the path is "-"
+pub(crate) fn read_command_script<R: Read>(
+    path: &path::Path,
+    mut stdin: R,
+) -> Result<String, Error> {
+    let mut script = String::new();
+    let result = if path == path::Path::new("-") {
+        stdin.read_to_string(&mut script)
+    } else {
+        File::open(path).and_then(|mut f| f.read_to_string(&mut script))
+    };
+    result.map_err(|e| FlokiError::ProblemReadingCommandFile {
+        name: path.display().to_string(),
+        error: e,
+    })?;
+    Ok(script)
+}
+
 /// Add mounts for each of the passed in volumes
 fn configure_volumes(
     cmd: DockerCommandBuilder,
@@ -75,6 +377,229 @@ fn configure_volumes(
     cmd
 }
 
+/// Hide each of `paths` in the container behind an empty anonymous volume
+fn configure_exclude(
+    mut cmd: DockerCommandBuilder,
+    paths: &[path::PathBuf],
+) -> DockerCommandBuilder {
+    for path in paths {
+        cmd = cmd.add_anonymous_volume(path);
+    }
+    cmd
+}
+
+/// Mount a docker volume for the project at each of `paths`
+fn configure_persisted_paths(
+    mut cmd: DockerCommandBuilder,
+    config_filepath: &path::Path,
+    paths: &[path::PathBuf],
+) -> DockerCommandBuilder {
+    for (name, path) in persisted_path_volumes(config_filepath, paths) {
+        cmd = cmd.add_volume((&path::PathBuf::from(name), path));
+    }
+    cmd
+}
+
+/// Mount the floki configuration read-only in the container, and point
+/// FLOKI_CONFIG at it
+fn configure_config_mount(
+    cmd: DockerCommandBuilder,
+    config_file: &path::Path,
+    mount: &path::Path,
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+    let mounts = config_mounts(config_file, mount);
+    for (src, dst) in mounts.iter() {
+        cmd = cmd.add_read_only_volume((src, dst));
+    }
+    if let Some((_, config)) = mounts.first() {
+        cmd = cmd.add_environment("FLOKI_CONFIG", config);
+    }
+    cmd
+}
+
+/// The mount paths of the mounted volumes marked `chown`
+fn chown_targets(volumes: &BTreeMap<String, Volume>, mounted: &[&path::PathBuf]) -> Vec<String> {
+    volumes
+        .values()
+        .filter(|volume| volume.chown && mounted.contains(&&volume.mount))
+        .map(|volume| volume.mount.display().to_string())
+        .collect()
+}
+
+/// The user the floki container's command runs as, in docker's
+/// `user[:group]` form: the host user with forward_user, or otherwise the
+/// image's own user. None if that is root, which needs no volumes chowned.
+fn container_user(user: &spec::User, image_user: &str) -> Option<String> {
+    if user.forward {
+        return Some(format!("{}:{}", user.uid, user.gid));
+    }
+    match image_user.split(':').next() {
+        None | Some("") | Some("0") | Some("root") => None,
+        Some(_) => Some(image_user.to_string()),
+    }
+}
+
+/// Wrap the container's command so that, starting as root, it first gives
+/// `targets` to `user` and then runs `command` with `shell` as that user.
+/// HOME is set from the user's passwd entry as docker would, and is `/`
+/// without one. This needs `setpriv` in the image.
+fn chown_then_run_as(user: &str, targets: &[String], shell: &str, command: &str) -> String {
+    let (name, group) = match user.split_once(':') {
+        Some((name, group)) => (name, shlex::quote(group).into_owned()),
+        None => (user, format!("\"$(id -g {})\"", shlex::quote(user))),
+    };
+    // Users without a passwd entry have no supplementary groups to set up
+    let groups = if name.chars().all(|c| c.is_ascii_digit()) {
+        "--clear-groups"
+    } else {
+        "--init-groups"
+    };
+    let name = shlex::quote(name);
+    let targets: Vec<String> = targets
+        .iter()
+        .map(|target| shlex::quote(target).into_owned())
+        .collect();
+    format!(
+        "group={group} && chown {name}:\"$group\" {targets} && \
+         home=\"$(awk -F: -v user={name} '$1 == user || $3 == user {{ print $6; exit }}' /etc/passwd 2>/dev/null)\"; \
+         export HOME=\"${{home:-/}}\" && \
+         exec setpriv --reuid={name} --regid=\"$group\" {groups} -- {shell} -c {command}",
+        group = group,
+        name = name,
+        targets = targets.join(" "),
+        groups = groups,
+        shell = shell,
+        command = shlex::quote(command)
+    )
+}
+
+/// Expose secrets to the container, either as environment variables or
+/// as read-only files. Secret files are removed when the returned handles
+/// are dropped.
+fn configure_secrets(
+    cmd: DockerCommandBuilder,
+    secrets: &[ResolvedSecret],
+) -> Result<(DockerCommandBuilder, Vec<NamedTempFile>), Error> {
+    let mut cmd = cmd; // Shadow as mutable
+    let mut files = Vec::new();
+    for secret in secrets.iter() {
+        match &secret.mount {
+            Some(mount) => {
+                let mut file = NamedTempFile::new()?;
+                file.write_all(secret.value.as_bytes())?;
+                file.flush()?;
+                cmd = cmd.add_read_only_volume((&file.path().to_path_buf(), mount));
+                files.push(file);
+            }
+            None => cmd = cmd.add_secret_environment(&secret.name, &secret.value),
+        }
+    }
+    Ok((cmd, files))
+}
+
+/// Add mounts for each of the overlay volumes. Where overlayfs isn't
+/// available, each lower directory is bind mounted in turn instead, so
+/// only the highest is visible.
+fn configure_overlay_volumes(
+    cmd: DockerCommandBuilder,
+    overlays: &[OverlayVolume],
+    overlay_supported: bool,
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+    for overlay in overlays.iter() {
+        if overlay_supported {
+            cmd = cmd
+                .add_docker_switch("--mount")
+                .add_docker_switch(overlay.mount_spec());
+        } else {
+            warn!(
+                "Overlay volumes are not supported on this platform - only the last \
+                 directory layered at {} will be visible",
+                overlay.mount.display()
+            );
+            for lower in overlay.lower.iter() {
+                cmd = cmd.add_volume((lower, overlay.mount));
+            }
+        }
+    }
+    cmd
+}
+
+/// Create the history directory and file if needed
+fn instantiate_history(history: &History) -> Result<(), Error> {
+    let file = history.host_file();
+    if let Some(parent) = file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)?;
+    Ok(())
+}
+
+/// Create the upper and work directories for overlay volumes if needed
+fn instantiate_overlay_volumes(overlays: &[OverlayVolume]) -> Result<(), Error> {
+    for overlay in overlays.iter() {
+        std::fs::create_dir_all(&overlay.upper)?;
+        std::fs::create_dir_all(&overlay.work)?;
+    }
+    Ok(())
+}
+
+/// Add DNS servers and extra hosts
+fn configure_dns(
+    cmd: DockerCommandBuilder,
+    dns: &[String],
+    add_hosts: &[String],
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+    for server in dns.iter() {
+        cmd = cmd.add_docker_switch("--dns").add_docker_switch(server);
+    }
+    for host in add_hosts.iter() {
+        cmd = cmd.add_docker_switch("--add-host").add_docker_switch(host);
+    }
+    cmd
+}
+
+/// Set the PID and IPC namespaces for the container
+fn configure_namespaces(
+    cmd: DockerCommandBuilder,
+    pid: Option<&str>,
+    ipc: Option<&str>,
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+    if let Some(pid) = pid {
+        cmd = cmd.add_docker_switch("--pid").add_docker_switch(pid);
+    }
+    if let Some(ipc) = ipc {
+        cmd = cmd.add_docker_switch("--ipc").add_docker_switch(ipc);
+    }
+    cmd
+}
+
+/// Set the logging driver for the container, and its options
+fn configure_log_driver(
+    cmd: DockerCommandBuilder,
+    driver: Option<&str>,
+    opts: &BTreeMap<String, String>,
+) -> DockerCommandBuilder {
+    let mut cmd = cmd; // Shadow as mutable
+    if let Some(driver) = driver {
+        cmd = cmd
+            .add_docker_switch("--log-driver")
+            .add_docker_switch(driver);
+    }
+    for (key, value) in opts.iter() {
+        cmd = cmd
+            .add_docker_switch("--log-opt")
+            .add_docker_switch(format!("{}={}", key, value));
+    }
+    cmd
+}
+
 /// Create the backing directories for floki volumes if needed
 fn instantiate_volumes(volumes: &[(path::PathBuf, &path::PathBuf)]) -> Result<(), Error> {
     for (src, _) in volumes.iter() {
@@ -83,10 +608,89 @@ fn instantiate_volumes(volumes: &[(path::PathBuf, &path::PathBuf)]) -> Result<()
     Ok(())
 }
 
+/// Check each of the required host tools can be found with `lookup`,
+/// reporting all of those missing together
+fn check_host_tools(tools: &[String], lookup: impl Fn(&str) -> bool) -> Result<(), Error> {
+    let missing: Vec<&str> = tools
+        .iter()
+        .map(String::as_str)
+        .filter(|tool| !lookup(tool))
+        .collect();
+    if missing.is_empty() {
+        Ok(())
+    } else {
+        Err(FlokiUserError::MissingHostTool {
+            tools: missing.join(", "),
+        }
+        .into())
+    }
+}
+
+/// Whether an executable called `tool` is in a directory on PATH
+fn on_path(tool: &str) -> bool {
+    use std::os::unix::fs::PermissionsExt;
+
+    let path = match std::env::var_os("PATH") {
+        Some(path) => path,
+        None => return false,
+    };
+    std::env::split_paths(&path).any(|directory| {
+        directory
+            .join(tool)
+            .metadata()
+            .map(|metadata| metadata.is_file() && metadata.permissions().mode() & 0o111 != 0)
+            .unwrap_or(false)
+    })
+}
+
+/// Run the pre_run commands on the host, in order, stopping at the first
+/// which fails. Unlike init commands, these never run in the container.
+fn run_pre_run_commands(commands: &[String], directory: &path::Path) -> Result<(), Error> {
+    for command in commands {
+        info!("Running pre_run command on host: {}", command);
+        let exit_status = Command::new("sh")
+            .args(["-c", command])
+            .current_dir(directory)
+            .status()
+            .map_err(|e| FlokiError::FailedToLaunchPreRunCommand {
+                command: command.clone(),
+                error: e,
+            })?;
+        if !exit_status.success() {
+            return Err(FlokiError::PreRunCommandFailed {
+                exit_status: FlokiSubprocessExitStatus {
+                    process_description: format!("pre_run command '{}'", command),
+                    exit_status,
+                },
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// A command adding directories to the front of PATH, or None if there
+/// are none to add
+fn path_export(directories: &[path::PathBuf]) -> Option<String> {
+    if directories.is_empty() {
+        return None;
+    }
+    let directories: Vec<String> = directories
+        .iter()
+        .map(|dir| dir.display().to_string())
+        .collect();
+    Some(format!(
+        "export PATH={}:\"$PATH\"",
+        shlex::quote(&directories.join(":"))
+    ))
+}
+
 /// Turn the init section of a floki.yaml file into a command
-/// that can be given to a shell
-fn subshell_command(init: &[String], command: &str) -> String {
-    let mut args: Vec<&str> = init.iter().map(|s| s as &str).collect::<Vec<&str>>();
+/// that can be given to a shell, setting the umask first if one is given
+fn subshell_command(umask: Option<&str>, init: &[String], command: &str) -> String {
+    let umask = umask.map(|umask| format!("umask {}", umask));
+    let mut args: Vec<&str> = umask.iter().map(|s| s as &str).collect::<Vec<&str>>();
+    args.extend(init.iter().map(|s| s as &str));
     args.push(command);
     args.join(" && ")
 }
@@ -104,4 +708,454 @@ mod test {
 
         assert!(result == expected);
     }
+
+    #[test]
+    fn test_render_template() -> Result<(), Error> {
+        let mut template_vars = BTreeMap::new();
+        template_vars.insert("tag".to_string(), "v1.2".to_string());
+        let variables =
+            template_variables(&template_vars, path::Path::new("/src/myproject"), "rust:1");
+
+        assert_eq!(
+            render_template(
+                "echo {{project_name}} {{ image }} {{tag}} {{tag}}",
+                &variables
+            )?,
+            "echo myproject rust:1 v1.2 v1.2"
+        );
+        assert_eq!(
+            render_template("no placeholders {{ here", &variables)?,
+            "no placeholders {{ here"
+        );
+        for literal in &[
+            "docker ps --format '{{.Names}}'",
+            "docker inspect -f '{{ json .Config }}'",
+            "echo {{}} {{ }}",
+        ] {
+            assert_eq!(render_template(literal, &variables)?, *literal);
+        }
+        assert_eq!(
+            render_template(
+                "docker ps -f name={{project_name}} --format {{.ID}}",
+                &variables
+            )?,
+            "docker ps -f name=myproject --format {{.ID}}"
+        );
+
+        let err = render_template("echo {{missing}}", &variables).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::UnknownTemplateVariable { name, .. }) if name == "missing"
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_command_prefix() {
+        let shell = prefixed_shell("bash", Some("ts"));
+        let init = vec!["echo init".to_string()];
+
+        // floki run
+        let command = command_in_shell(&shell, &["make".to_string()]);
+        assert_eq!(
+            subshell_command(None, &init, &command),
+            "echo init && ts bash -c \"make\""
+        );
+
+        // Interactive launches
+        let command = interactive_command(&shell, Some("hello"));
+        assert_eq!(
+            subshell_command(None, &init, &command),
+            "echo init && printf '%s\\n' hello && ts bash"
+        );
+
+        assert_eq!(prefixed_shell("bash", None), "bash");
+    }
+
+    #[test]
+    fn test_path_export() {
+        assert_eq!(path_export(&[]), None);
+        assert_eq!(
+            path_export(&["/src/bin".into(), "/src/tools".into()]).as_deref(),
+            Some("export PATH=/src/bin:/src/tools:\"$PATH\"")
+        );
+        assert_eq!(
+            path_export(&["/my src/bin".into()]).as_deref(),
+            Some("export PATH=\"/my src/bin\":\"$PATH\"")
+        );
+    }
+
+    #[test]
+    fn test_subshell_command() {
+        let init = vec!["echo init".to_string()];
+        assert_eq!(subshell_command(None, &init, "bash"), "echo init && bash");
+        assert_eq!(
+            subshell_command(Some("027"), &init, "bash"),
+            "umask 027 && echo init && bash"
+        );
+        assert_eq!(
+            subshell_command(Some("027"), &[], "make"),
+            "umask 027 && make"
+        );
+    }
+
+    #[test]
+    fn test_interactive_command() {
+        assert_eq!(interactive_command("bash", None), "bash");
+        assert_eq!(
+            interactive_command("bash", Some("Welcome to the build environment")),
+            "printf '%s\\n' \"Welcome to the build environment\" && bash"
+        );
+        // floki run doesn't show the message of the day
+        let command = vec![String::from("make")];
+        assert!(!command_in_shell("bash", &command).contains("printf"));
+    }
+
+    #[test]
+    fn test_script_in_shell() {
+        let script = "echo \"hello $USER\"\nls";
+        assert_eq!(
+            script_in_shell("bash", script, false),
+            "bash -c \"echo \\\"hello \\$USER\\\"\nls\""
+        );
+        assert!(script_in_shell("bash", script, true).starts_with("bash -c \"set -e\n"));
+    }
+
+    #[test]
+    fn test_read_command_script_from_file() -> Result<(), Error> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        write!(file, "echo foo\necho bar\n")?;
+        let script = read_command_script(file.path(), std::io::empty())?;
+        assert_eq!(script, "echo foo\necho bar\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_command_script_from_stdin() -> Result<(), Error> {
+        let stdin = std::io::Cursor::new("echo foo\n");
+        let script = read_command_script(path::Path::new("-"), stdin)?;
+        assert_eq!(script, "echo foo\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_command_script_missing_file() {
+        let result = read_command_script(path::Path::new("/no/such/script"), std::io::empty());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_check_host_tools() {
+        let tools: Vec<String> = vec!["git".into(), "make".into(), "jq".into()];
+        assert!(check_host_tools(&tools, |_| true).is_ok());
+        assert!(check_host_tools(&[], |_| false).is_ok());
+
+        let err = check_host_tools(&tools, |tool| tool == "git").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::MissingHostTool { tools }) if tools == "make, jq"
+        ));
+    }
+
+    #[test]
+    fn test_on_path() {
+        assert!(on_path("sh"));
+        assert!(!on_path("floki-no-such-tool"));
+    }
+
+    #[test]
+    fn test_run_pre_run_commands() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let commands = vec!["touch first".to_string(), "touch second".to_string()];
+        run_pre_run_commands(&commands, tmp_dir.path())?;
+        assert!(tmp_dir.path().join("first").exists());
+        assert!(tmp_dir.path().join("second").exists());
+        Ok(())
+    }
+
+    #[test]
+    fn test_run_pre_run_commands_failure() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let commands = vec!["exit 3".to_string(), "touch never".to_string()];
+        let err = run_pre_run_commands(&commands, tmp_dir.path()).unwrap_err();
+        assert!(err.to_string().contains("exited with return code 3"));
+        assert!(!tmp_dir.path().join("never").exists());
+        Ok(())
+    }
+
+    fn overlay_volume(target: &path::PathBuf) -> OverlayVolume<'_> {
+        OverlayVolume {
+            name: "tools",
+            lower: vec!["/base".into(), "/plugin".into()],
+            upper: "/work/upper".into(),
+            work: "/work/work".into(),
+            mount: target,
+        }
+    }
+
+    #[test]
+    fn test_configure_overlay_volumes() {
+        let target = path::PathBuf::from("/opt/tools");
+        let overlays = vec![overlay_volume(&target)];
+        let cmd = configure_overlay_volumes(DockerCommandBuilder::new("image"), &overlays, true);
+        assert_eq!(
+            args_of(&cmd),
+            vec!["--mount".to_string(), overlays[0].mount_spec()]
+        );
+    }
+
+    #[test]
+    fn test_configure_overlay_volumes_fallback() {
+        let target = path::PathBuf::from("/opt/tools");
+        let overlays = vec![overlay_volume(&target)];
+        let cmd = configure_overlay_volumes(DockerCommandBuilder::new("image"), &overlays, false);
+        assert_eq!(
+            args_of(&cmd),
+            vec!["-v", "/base:/opt/tools", "-v", "/plugin:/opt/tools"]
+        );
+    }
+
+    #[test]
+    fn test_chown_targets() -> Result<(), Error> {
+        let volumes: BTreeMap<String, Volume> = serde_yaml::from_str(
+            "cache:\n  mount: /cache\n  chown: true\nother:\n  mount: /other\nskipped:\n  mount: /skipped\n  chown: true",
+        )?;
+        let (cache, other) = (path::PathBuf::from("/cache"), path::PathBuf::from("/other"));
+
+        assert_eq!(chown_targets(&volumes, &[&cache, &other]), vec!["/cache"]);
+        assert!(chown_targets(&volumes, &[&other]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_container_user() {
+        let mut user = spec::User {
+            forward: true,
+            uid: nix::unistd::Uid::from_raw(1000),
+            gid: nix::unistd::Gid::from_raw(1001),
+        };
+        assert_eq!(container_user(&user, "builder"), Some("1000:1001".into()));
+
+        user.forward = false;
+        assert_eq!(container_user(&user, "builder"), Some("builder".into()));
+        assert_eq!(container_user(&user, "500:500"), Some("500:500".into()));
+        for root in &["", "root", "0", "0:0", "root:staff"] {
+            assert_eq!(container_user(&user, root), None, "{}", root);
+        }
+    }
+
+    #[test]
+    fn test_chown_then_run_as() {
+        let targets = vec!["/cache".to_string(), "/my cache".to_string()];
+        let command = chown_then_run_as("1000:1001", &targets, "sh", "echo $HOME");
+        assert!(command.starts_with("group=1001 && chown 1000:\"$group\" /cache \"/my cache\" && "));
+        assert!(command.contains("-v user=1000 "));
+        assert!(command.ends_with(
+            "exec setpriv --reuid=1000 --regid=\"$group\" --clear-groups -- sh -c \"echo \\$HOME\""
+        ));
+
+        // A user named by the image takes its own group and groups
+        let command = chown_then_run_as("builder", &targets, "bash", "make");
+        assert!(
+            command.starts_with("group=\"$(id -g builder)\" && chown builder:\"$group\" /cache")
+        );
+        assert!(command.ends_with(
+            "exec setpriv --reuid=builder --regid=\"$group\" --init-groups -- bash -c make"
+        ));
+    }
+
+    #[test]
+    fn test_configure_dns() {
+        let dns = vec!["10.0.0.53".to_string()];
+        let add_hosts = vec!["db:10.0.0.2".to_string(), "cache:10.0.0.3".to_string()];
+        let cmd = configure_dns(DockerCommandBuilder::new("image"), &dns, &add_hosts);
+        assert_eq!(
+            args_of(&cmd),
+            vec![
+                "--dns",
+                "10.0.0.53",
+                "--add-host",
+                "db:10.0.0.2",
+                "--add-host",
+                "cache:10.0.0.3"
+            ]
+        );
+
+        let cmd = configure_dns(DockerCommandBuilder::new("image"), &[], &[]);
+        assert!(args_of(&cmd).is_empty());
+    }
+
+    #[test]
+    fn test_configure_namespaces() {
+        let cmd = configure_namespaces(
+            DockerCommandBuilder::new("image"),
+            Some("host"),
+            Some("container:other"),
+        );
+        assert_eq!(
+            args_of(&cmd),
+            vec!["--pid", "host", "--ipc", "container:other"]
+        );
+
+        let cmd = configure_namespaces(DockerCommandBuilder::new("image"), None, Some("host"));
+        assert_eq!(args_of(&cmd), vec!["--ipc", "host"]);
+
+        let cmd = configure_namespaces(DockerCommandBuilder::new("image"), None, None);
+        assert!(args_of(&cmd).is_empty());
+    }
+
+    #[test]
+    fn test_configure_log_driver() {
+        let mut opts = BTreeMap::new();
+        opts.insert("max-size".to_string(), "10m".to_string());
+        opts.insert("max-file".to_string(), "3".to_string());
+        let cmd =
+            configure_log_driver(DockerCommandBuilder::new("image"), Some("json-file"), &opts);
+        assert_eq!(
+            args_of(&cmd),
+            vec![
+                "--log-driver",
+                "json-file",
+                "--log-opt",
+                "max-file=3",
+                "--log-opt",
+                "max-size=10m"
+            ]
+        );
+
+        let cmd = configure_log_driver(
+            DockerCommandBuilder::new("image"),
+            Some("none"),
+            &BTreeMap::new(),
+        );
+        assert_eq!(args_of(&cmd), vec!["--log-driver", "none"]);
+
+        let cmd = configure_log_driver(DockerCommandBuilder::new("image"), None, &BTreeMap::new());
+        assert!(args_of(&cmd).is_empty());
+    }
+
+    fn args_of(cmd: &DockerCommandBuilder) -> Vec<String> {
+        cmd.docker_args()
+            .iter()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn test_configure_exclude() {
+        let paths = vec![
+            path::PathBuf::from("/src/node_modules"),
+            path::PathBuf::from("/src/target"),
+        ];
+        let cmd = configure_exclude(DockerCommandBuilder::new("image"), &paths);
+        assert_eq!(
+            args_of(&cmd),
+            vec!["-v", "/src/node_modules", "-v", "/src/target"]
+        );
+    }
+
+    #[test]
+    fn test_configure_persisted_paths() {
+        let config = path::Path::new("/floki/root/floki.yaml");
+        let paths = vec![path::PathBuf::from("/opt/toolchain")];
+        let cmd = configure_persisted_paths(DockerCommandBuilder::new("image"), config, &paths);
+        let args = args_of(&cmd);
+        assert_eq!(args.len(), 2);
+        assert_eq!(args[0], "-v");
+        assert!(args[1].starts_with("floki-"));
+        assert!(args[1].contains("-opt-toolchain-"));
+        assert!(args[1].ends_with(":/opt/toolchain"));
+
+        // The same volume is mounted on every run
+        let again = configure_persisted_paths(DockerCommandBuilder::new("image"), config, &paths);
+        assert_eq!(args_of(&again), args);
+    }
+
+    #[test]
+    fn test_configure_config_mount() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("floki.yaml");
+        std::fs::write(&config, "image: foo")?;
+        let mount = path::Path::new("/floki-config");
+
+        let cmd = configure_config_mount(DockerCommandBuilder::new("image"), &config, mount);
+        assert_eq!(
+            args_of(&cmd),
+            vec![
+                "-v".to_string(),
+                format!("{}:/floki-config/floki.yaml:ro", config.display()),
+                "-e".to_string(),
+                "FLOKI_CONFIG=/floki-config/floki.yaml".to_string(),
+            ]
+        );
+
+        // Local configuration is mounted alongside, if there is any
+        let local = directory.path().join("floki.local.yaml");
+        std::fs::write(&local, "")?;
+        let cmd = configure_config_mount(DockerCommandBuilder::new("image"), &config, mount);
+        assert!(args_of(&cmd).contains(&format!(
+            "{}:/floki-config/floki.local.yaml:ro",
+            local.display()
+        )));
+        Ok(())
+    }
+
+    #[test]
+    fn test_configure_secrets() -> Result<(), Error> {
+        let secrets = vec![
+            ResolvedSecret {
+                name: "TOKEN".into(),
+                value: "hunter2".into(),
+                mount: None,
+            },
+            ResolvedSecret {
+                name: "token-file".into(),
+                value: "hunter3".into(),
+                mount: Some("/run/token".into()),
+            },
+        ];
+
+        let (cmd, files) = configure_secrets(DockerCommandBuilder::new("image"), &secrets)?;
+        let args = args_of(&cmd);
+
+        assert_eq!(files.len(), 1);
+        assert_eq!(std::fs::read_to_string(files[0].path())?, "hunter3");
+        let mount = format!("{}:/run/token:ro", files[0].path().display());
+        assert!(args.contains(&mount));
+        assert!(args.windows(2).any(|w| w == ["-e", "TOKEN"]));
+        // Secret values never appear on the command line
+        assert!(!args.iter().any(|a| a.contains("hunter")));
+
+        Ok(())
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_dind_and_run_spans() {
+        use telemetry::spans::{test::InMemoryExporter, Session};
+
+        // docker rejects the uppercase name without contacting a registry
+        let image = "floki/INVALID";
+        let root = path::PathBuf::from("/src");
+        let exporter = InMemoryExporter::default();
+        {
+            let _session = Session::install(Box::new(exporter.clone()));
+            let dind = Dind::new(image, (&root, &root), None, None);
+            assert!(start_dind(image, dind, command::DockerOutput::Capture).is_err());
+            let cmd = DockerCommandBuilder::new(image);
+            assert!(run_container(&cmd, &["true"], None).is_err());
+        }
+
+        let spans = exporter.0.borrow();
+        let outcomes: Vec<(&str, bool)> = spans
+            .iter()
+            .map(|span| (span.name, span.error.is_some()))
+            .collect();
+        // The dind image isn't available, so is pulled while getting ready
+        assert_eq!(
+            outcomes,
+            vec![("pull", true), ("dind-ready", true), ("run", true)]
+        );
+        assert_eq!(spans[0].parent_span_id.as_ref(), Some(&spans[1].span_id));
+    }
 }
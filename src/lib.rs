@@ -0,0 +1,645 @@
+//! floki - the development container launcher
+//!
+//! The floki binary is a thin wrapper around `run_floki_from_args`, which
+//! can also be used to drive floki from other tools.
+#[macro_use]
+extern crate log;
+
+mod clean;
+pub mod cli;
+mod command;
+mod config;
+mod dind;
+mod environment;
+pub mod errors;
+pub mod events;
+mod history;
+mod image;
+mod init;
+mod interpret;
+mod logging;
+mod secrets;
+mod spec;
+mod telemetry;
+mod variables;
+mod version;
+mod volumes;
+
+use anyhow::Error;
+use cli::{Cli, Subcommand};
+use command::DockerOutput;
+use config::FlokiConfig;
+use environment::Environment;
+use events::{EventHandler, Phase};
+use structopt::StructOpt;
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Decide which commands to run given the input from the shell, reporting
+/// progress to `events`
+pub fn run_floki_from_args(args: &Cli, events: &dyn EventHandler) -> Result<(), Error> {
+    debug!("Got command line arguments: {:?}", &args);
+    let _telemetry = telemetry::Session::from_env();
+
+    if args.local {
+        return Err(errors::FlokiUserError::LocalFlagRemoved {}.into());
+    }
+
+    // Check whether a floki config file exists, if so, load the extra command
+    // and append to the inner command.
+
+    // Dispatch appropriate subcommand
+    match &args.subcommand {
+        // Pull the image in the configuration file
+        Some(Subcommand::Pull { quiet }) => {
+            let (env, config) = load_config(args, events)?;
+            let name = config.image.name()?;
+            image::pull_image(&name, *quiet, DockerOutput::from_flag(args.verbose_docker))?;
+            // Pulling restarts the clock for refresh_tag_after
+            image::PullRecords::new(&env.floki_workspace)
+                .record(&name, &image::inspect_image(&name)?.id)?;
+            let digest = image::image_digest(&name)?;
+            if *quiet {
+                println!("{}", digest);
+            } else {
+                println!("{}@{}", name, digest);
+            }
+            Ok(())
+        }
+
+        // Run a command in the floki container
+        Some(Subcommand::Run {
+            command,
+            command_file,
+            exit_on_error,
+            matrix,
+            output,
+        }) => {
+            let (env, config) = load_config(args, events)?;
+            let script = match command_file {
+                Some(path) => Some(interpret::read_command_script(path, std::io::stdin())?),
+                None => None,
+            };
+
+            if *matrix {
+                // The configuration is resolved once, and each run differs
+                // only in its image
+                let images = config.image_matrix.clone();
+                let commands = images
+                    .iter()
+                    .map(|name| {
+                        run_command(
+                            &config,
+                            &env,
+                            name,
+                            command,
+                            script.as_deref(),
+                            *exit_on_error,
+                        )
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                let base = prepare_spec(args, config, env)?;
+                let run_id = format!("floki-matrix-{}", uuid::Uuid::new_v4().to_simple());
+                return run_matrix(&images, |index, name| {
+                    let mut spec = base.clone();
+                    spec.image = image::Image::Name(name.to_string());
+                    spec.container_name = Some(format!("{}-{}", run_id, index));
+                    spec.output = output.as_deref().map(|output| matrix_output(output, index));
+                    launch(spec, &commands[index], args.dry_run, events)
+                });
+            }
+
+            let image = config.image.name()?;
+            let inner_command = run_command(
+                &config,
+                &env,
+                &image,
+                command,
+                script.as_deref(),
+                *exit_on_error,
+            )?;
+            let mut spec = prepare_spec(args, config, env)?;
+            spec.output = output.clone();
+            launch(spec, &inner_command, args.dry_run, events)
+        }
+
+        // Remove stale floki-built images
+        Some(Subcommand::Clean {
+            images,
+            projects,
+            keep,
+            dry_run,
+        }) => {
+            if !images {
+                warn!("Nothing to clean - use --images to remove stale floki-built images");
+                return Ok(());
+            }
+            let env = match Environment::gather(&args.config_file, &args.from) {
+                Ok(env) => env,
+                // Other projects can still be cleaned up from outside one
+                Err(e) if is_missing_config(&e) && !projects.is_empty() => {
+                    Environment::gather_without_config(&args.from)?
+                }
+                Err(e) => return Err(e),
+            };
+            let current = Some(env.config_file).filter(|file| file.exists());
+            clean::clean_images(
+                &clean::known_config_files(current, projects),
+                env.default_registry.as_deref(),
+                *keep,
+                *dry_run,
+            )
+        }
+
+        // Print the container's environment for use on the host
+        Some(Subcommand::ShellEnv { shell }) => {
+            let (env, config) = load_config(args, events)?;
+            for (name, value) in variables::configured_variables(&config, &env.floki_root)? {
+                println!("{}", variables::export_statement(*shell, &name, &value));
+            }
+            Ok(())
+        }
+
+        // Write a starter configuration file
+        Some(Subcommand::Init { force }) => {
+            let directory = match &args.from {
+                Some(directory) => directory.clone(),
+                None => env::current_dir()?,
+            };
+            let file = init::write_starter_config(&directory, args.image.as_deref(), *force)?;
+            println!("Wrote {}", file.display());
+            Ok(())
+        }
+
+        Some(Subcommand::Completion { shell }) => {
+            Cli::clap().gen_completions_to("floki", *shell, &mut std::io::stdout());
+            Ok(())
+        }
+
+        // Launch an interactive floki shell (the default)
+        None => {
+            let (env, config) = load_config(args, events)?;
+            let shell = interpret::prefixed_shell(
+                config.shell.inner_shell(),
+                config.command_prefix.as_deref(),
+            );
+            let inner_command = interpret::interactive_command(&shell, config.motd.as_deref());
+            let inner_command = append_global_config(&inner_command);
+            launch(
+                prepare_spec(args, config, env)?,
+                &inner_command,
+                args.dry_run,
+                events,
+            )
+        }
+    }
+}
+
+/// The inner command for `floki run` in `image`, running either the given
+/// command or a script in the configured shell
+fn run_command(
+    config: &FlokiConfig,
+    env: &Environment,
+    image: &str,
+    command: &[String],
+    script: Option<&str>,
+    exit_on_error: bool,
+) -> Result<String, Error> {
+    let shell =
+        interpret::prefixed_shell(config.shell.inner_shell(), config.command_prefix.as_deref());
+    let inner_command = match script {
+        Some(script) => interpret::script_in_shell(&shell, script, exit_on_error),
+        None => {
+            let variables =
+                interpret::template_variables(&config.template_vars, &env.floki_root, image);
+            let command = command
+                .iter()
+                .map(|arg| interpret::render_template(arg, &variables))
+                .collect::<Result<Vec<_>, _>>()?;
+            interpret::command_in_shell(&shell, &command)
+        }
+    };
+    Ok(append_global_config(&inner_command))
+}
+
+/// The file to write the output of one run of a matrix to, so each run
+/// keeps its own output - e.g. `build.log` becomes `build.0.log`
+fn matrix_output(output: &Path, index: usize) -> PathBuf {
+    let mut name = output.file_stem().unwrap_or_default().to_os_string();
+    name.push(format!(".{}", index));
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    output.with_file_name(name)
+}
+
+/// Run once for each image in the matrix, carrying on past failures, and
+/// print a summary of the results
+fn run_matrix<F>(images: &[String], mut run: F) -> Result<(), Error>
+where
+    F: FnMut(usize, &str) -> Result<(), Error>,
+{
+    if images.is_empty() {
+        return Err(errors::FlokiUserError::EmptyImageMatrix {}.into());
+    }
+
+    let results: Vec<(&String, Result<(), Error>)> = images
+        .iter()
+        .enumerate()
+        .map(|(index, image)| (image, run(index, image)))
+        .collect();
+
+    println!("Matrix results:");
+    for (image, result) in results.iter() {
+        match result {
+            Ok(()) => println!("  {}: ok", image),
+            Err(e) => println!("  {}: failed - {}", image, e),
+        }
+    }
+
+    let failed = results.iter().filter(|(_, result)| result.is_err()).count();
+    if failed > 0 {
+        return Err(errors::FlokiError::MatrixRunFailed {
+            failed,
+            total: images.len(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// The spec for running the configuration, with the command line options
+/// which affect how the container is run
+fn prepare_spec(
+    args: &Cli,
+    config: FlokiConfig,
+    env: Environment,
+) -> Result<spec::FlokiSpec, Error> {
+    let mut spec = spec::FlokiSpec::from(config, env)?;
+    spec.check_docker_version = !args.skip_version_check;
+    spec.docker_output = DockerOutput::from_flag(args.verbose_docker);
+    Ok(spec)
+}
+
+/// Run the floki container, or describe what would be run for a dry run
+fn launch(
+    spec: spec::FlokiSpec,
+    inner_command: &str,
+    dry_run: bool,
+    events: &dyn EventHandler,
+) -> Result<(), Error> {
+    if dry_run {
+        events.phase(&Phase::DryRun);
+        println!("Would run '{}' in {}", inner_command, spec.image.name()?);
+        return Ok(());
+    }
+    check_not_nested(
+        env::var_os(interpret::IN_CONTAINER_VARIABLE).is_some(),
+        spec.dind.is_some(),
+    )?;
+    interpret::run_floki_container(&spec, inner_command, events)
+}
+
+/// Refuse to launch from inside a floki container unless docker is made
+/// available, since a floki container has no docker daemon of its own
+fn check_not_nested(in_container: bool, dind: bool) -> Result<(), Error> {
+    if in_container && !dind {
+        return Err(errors::FlokiUserError::NestedFlokiWithoutDind {}.into());
+    }
+    Ok(())
+}
+
+/// Gather the environment and load its configuration, applying any command
+/// line options which extend it. If there's no configuration file, an image
+/// given with `--image` is run with the default configuration.
+fn load_config(args: &Cli, events: &dyn EventHandler) -> Result<(Environment, FlokiConfig), Error> {
+    events.phase(&Phase::ResolvingConfig);
+    telemetry::in_span("resolve-config", || resolve_config(args))
+}
+
+fn resolve_config(args: &Cli) -> Result<(Environment, FlokiConfig), Error> {
+    let (env, mut config) = match Environment::gather(&args.config_file, &args.from) {
+        Ok(env) => {
+            let mut config =
+                FlokiConfig::from_file_with_profile(&env.config_file, args.profile.as_deref())?;
+            config.use_default_registry(env.default_registry.as_deref());
+            if let Some(other) = &args.reuse_image_from {
+                config.reuse_image_from = Some(env.current_directory.join(other));
+                config.resolve_reused_image(&env.config_file)?;
+            }
+            if let Some(image) = &args.image {
+                config.image = image::Image::Name(image.clone());
+            }
+            (env, config)
+        }
+        Err(e) => match &args.image {
+            Some(image) if is_missing_config(&e) => {
+                debug!(
+                    "No configuration file found - running {} with defaults",
+                    image
+                );
+                config_for_image(image, &args.from)?
+            }
+            _ => return Err(e),
+        },
+    };
+    if let Some(file) = &args.env_passthrough_file {
+        config
+            .forward_env
+            .extend(variables::read_passthrough_file(file)?);
+    }
+    Ok((env, config))
+}
+
+fn is_missing_config(error: &Error) -> bool {
+    matches!(
+        error.downcast_ref::<errors::FlokiError>(),
+        Some(errors::FlokiError::ProblemFindingConfigYaml {})
+    )
+}
+
+/// The environment and configuration for running an image without a
+/// configuration file
+fn config_for_image(
+    image: &str,
+    from: &Option<std::path::PathBuf>,
+) -> Result<(Environment, FlokiConfig), Error> {
+    Ok((
+        Environment::gather_without_config(from)?,
+        FlokiConfig::for_image(image)?,
+    ))
+}
+
+/// Searches for a startup script in $HOME/.floki, if found, will run commands
+/// in the floki container when the container starts up.
+fn append_global_config(command: &str) -> String {
+    // Retrieve the name of the home directory.
+    let home = match env::var("HOME") {
+        Ok(val) => val,
+        Err(_) => return command.to_string(),
+    };
+
+    // Check for the existence of the startup script.
+    let filepath = format!("{}/.floki/startup.sh", home.as_str());
+    let startup_script_exists = Path::new(&filepath).exists();
+    if startup_script_exists {
+        let contents = match fs::read_to_string(filepath) {
+            Ok(data) => data,
+            Err(_) => return command.to_string(),
+        };
+        return format!("{} && {}", contents.trim(), command);
+    }
+
+    command.to_string()
+}
+
+/// Configure the logger
+pub fn configure_logging(verbosity: u8, timestamps: bool) -> Result<(), Error> {
+    let level = match verbosity {
+        0 => log::LevelFilter::Warn,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        3 => log::LevelFilter::Trace,
+        _ => {
+            return Err(
+                errors::FlokiUserError::InvalidVerbositySetting { setting: verbosity }.into(),
+            )
+        }
+    };
+    if timestamps {
+        return logging::init_elapsed_logger(level);
+    }
+    simplelog::TermLogger::init(
+        level,
+        simplelog::Config::default(),
+        simplelog::TerminalMode::Stderr,
+        simplelog::ColorChoice::Auto,
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::cell::RefCell;
+
+    /// Records the phases it is told about
+    #[derive(Default)]
+    struct RecordingEvents(RefCell<Vec<Phase>>);
+
+    impl EventHandler for RecordingEvents {
+        fn phase(&self, phase: &Phase) {
+            self.0.borrow_mut().push(phase.clone());
+        }
+    }
+
+    #[test]
+    fn test_local_flag_is_an_error() {
+        let args = Cli::from_iter(&["floki", "--local", "completion", "bash"]);
+        let err = run_floki_from_args(&args, &events::NoEvents).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::FlokiUserError>(),
+            Some(errors::FlokiUserError::LocalFlagRemoved {})
+        ));
+    }
+
+    #[test]
+    fn test_check_not_nested() {
+        assert!(check_not_nested(false, false).is_ok());
+        assert!(check_not_nested(false, true).is_ok());
+        assert!(check_not_nested(true, true).is_ok());
+
+        let err = check_not_nested(true, false).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::FlokiUserError>(),
+            Some(errors::FlokiUserError::NestedFlokiWithoutDind {})
+        ));
+    }
+
+    #[test]
+    fn test_matrix_output() {
+        let cases = [
+            ("build.log", 0, "build.0.log"),
+            ("logs/build.log", 2, "logs/build.2.log"),
+            ("/tmp/output", 1, "/tmp/output.1"),
+            ("logs/build.tar.gz", 1, "logs/build.tar.1.gz"),
+        ];
+        for (output, index, expected) in cases.iter() {
+            assert_eq!(
+                matrix_output(Path::new(output), *index),
+                Path::new(expected)
+            );
+        }
+        // Every run gets a different file
+        assert_ne!(
+            matrix_output(Path::new("build.log"), 0),
+            matrix_output(Path::new("build.log"), 1)
+        );
+    }
+
+    #[test]
+    fn test_run_matrix() {
+        let images = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+
+        let mut runs = Vec::new();
+        let result = run_matrix(&images, |index, image| {
+            runs.push((index, image.to_string()));
+            if image == "b" {
+                Err(errors::FlokiUserError::LocalFlagRemoved {}.into())
+            } else {
+                Ok(())
+            }
+        });
+        // Every image is run, even after a failure
+        assert_eq!(
+            runs,
+            vec![
+                (0, "a".to_string()),
+                (1, "b".to_string()),
+                (2, "c".to_string())
+            ]
+        );
+        assert!(matches!(
+            result.unwrap_err().downcast_ref::<errors::FlokiError>(),
+            Some(errors::FlokiError::MatrixRunFailed {
+                failed: 1,
+                total: 3
+            })
+        ));
+
+        assert!(run_matrix(&images, |_, _| Ok(())).is_ok());
+        assert!(run_matrix(&[], |_, _| Ok(())).is_err());
+    }
+
+    #[test]
+    fn test_config_for_image() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let root = fs::canonicalize(directory.path())?;
+
+        let (env, config) = config_for_image("alpine", &Some(root.clone()))?;
+        let spec = spec::FlokiSpec::from(config, env)?;
+        assert_eq!(spec.image.name()?, "alpine");
+        assert_eq!(spec.paths.root, root);
+        assert_eq!(spec.paths.internal_working_directory, Path::new("/src"));
+        assert_eq!(spec.mount, Path::new("/src"));
+        assert_eq!(spec.shell.inner_shell(), "sh");
+        assert!(spec.volumes.is_empty());
+        assert!(spec.dind.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_reuse_image_from_flag() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let root = fs::canonicalize(directory.path())?;
+        for project in &["app", "base"] {
+            fs::create_dir(root.join(project))?;
+        }
+        fs::write(root.join("app/floki.yaml"), "image: debian:sid")?;
+        fs::write(
+            root.join("base/floki.yaml"),
+            "image:\n  build:\n    name: base\n    dockerfile: Dockerfile",
+        )?;
+
+        // The path is relative to --from, not wherever the tests are run
+        let args = Cli::from_iter(&[
+            "floki".as_ref(),
+            "--from".as_ref(),
+            root.join("app").as_os_str(),
+            "--reuse-image-from".as_ref(),
+            "../base/floki.yaml".as_ref(),
+        ] as &[&std::ffi::OsStr]);
+        let (env, config) = resolve_config(&args)?;
+        let spec = spec::FlokiSpec::from(config, env)?;
+        assert_eq!(spec.image.name()?, "base:floki");
+        assert_eq!(spec.image_root, root.join("base"));
+        assert_eq!(spec.paths.root, root.join("app"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_missing_config_without_image() {
+        let missing = errors::FlokiError::ProblemFindingConfigYaml {}.into();
+        assert!(is_missing_config(&missing));
+        let other = errors::FlokiUserError::LocalFlagRemoved {}.into();
+        assert!(!is_missing_config(&other));
+    }
+
+    #[cfg(feature = "otel")]
+    #[test]
+    fn test_resolve_config_span() -> Result<(), Error> {
+        use telemetry::spans::{test::InMemoryExporter, Session};
+
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("floki.yaml");
+        fs::write(&config, "image: debian:sid")?;
+        let args = Cli::from_iter(&[
+            "floki".as_ref(),
+            "--config".as_ref(),
+            config.as_os_str(),
+            "--dry-run".as_ref(),
+        ] as &[&std::ffi::OsStr]);
+
+        let exporter = InMemoryExporter::default();
+        {
+            let _session = Session::install(Box::new(exporter.clone()));
+            run_floki_from_args(&args, &events::NoEvents)?;
+        }
+        let names: Vec<&str> = exporter.0.borrow().iter().map(|span| span.name).collect();
+        assert_eq!(names, vec!["resolve-config"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dry_run_phases() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("floki.yaml");
+        fs::write(&config, "image: debian:sid")?;
+
+        let args = Cli::from_iter(&[
+            "floki".as_ref(),
+            "--config".as_ref(),
+            config.as_os_str(),
+            "--dry-run".as_ref(),
+            "run".as_ref(),
+            "true".as_ref(),
+        ] as &[&std::ffi::OsStr]);
+        let events = RecordingEvents::default();
+        run_floki_from_args(&args, &events)?;
+
+        assert_eq!(
+            events.0.into_inner(),
+            vec![Phase::ResolvingConfig, Phase::DryRun]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_matrix_resolves_config_once() -> Result<(), Error> {
+        let directory = tempfile::tempdir()?;
+        let config = directory.path().join("floki.yaml");
+        fs::write(&config, "image: debian:sid\nimage_matrix: [alpine, ubuntu]")?;
+
+        let args = Cli::from_iter(&[
+            "floki".as_ref(),
+            "--config".as_ref(),
+            config.as_os_str(),
+            "--dry-run".as_ref(),
+            "run".as_ref(),
+            "--matrix".as_ref(),
+            "true".as_ref(),
+        ] as &[&std::ffi::OsStr]);
+        let events = RecordingEvents::default();
+        run_floki_from_args(&args, &events)?;
+
+        assert_eq!(
+            events.0.into_inner(),
+            vec![Phase::ResolvingConfig, Phase::DryRun, Phase::DryRun]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,58 @@
+/// A logger which prefixes each record with the time elapsed since floki started
+use anyhow::Error;
+use log::{LevelFilter, Log, Metadata, Record};
+use std::time::{Duration, Instant};
+
+struct ElapsedLogger {
+    level: LevelFilter,
+    start: Instant,
+}
+
+impl Log for ElapsedLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} [{}] {}",
+                format_elapsed(self.start.elapsed()),
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the elapsed time logger as the global logger
+pub(crate) fn init_elapsed_logger(level: LevelFilter) -> Result<(), Error> {
+    log::set_boxed_logger(Box::new(ElapsedLogger {
+        level,
+        start: Instant::now(),
+    }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Format an elapsed time as seconds with millisecond precision
+fn format_elapsed(elapsed: Duration) -> String {
+    format!("[{:>9.3}s]", elapsed.as_secs_f64())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_format_elapsed() {
+        assert_eq!(format_elapsed(Duration::from_millis(0)), "[    0.000s]");
+        assert_eq!(format_elapsed(Duration::from_millis(1234)), "[    1.234s]");
+        assert_eq!(
+            format_elapsed(Duration::from_micros(754_321_987)),
+            "[  754.322s]"
+        );
+    }
+}
@@ -0,0 +1,191 @@
+/// Resolution of secrets from the host keyring
+use crate::config;
+use crate::errors::FlokiUserError;
+use anyhow::Error;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path;
+
+/// A source of credentials
+pub(crate) trait Keyring {
+    /// Look up a credential, returning None if there is no such credential
+    fn get_password(&self, entry: &config::KeyringEntry) -> Result<Option<String>, Error>;
+}
+
+/// The operating system's keyring
+pub(crate) struct SystemKeyring;
+
+impl Keyring for SystemKeyring {
+    fn get_password(&self, entry: &config::KeyringEntry) -> Result<Option<String>, Error> {
+        let lookup_failed = |e: keyring::Error| FlokiUserError::KeyringLookupFailed {
+            service: entry.service.clone(),
+            user: entry.user.clone(),
+            error: e.to_string(),
+        };
+
+        match keyring::Entry::new(&entry.service, &entry.user).and_then(|e| e.get_password()) {
+            Ok(password) => Ok(Some(password)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(lookup_failed(e).into()),
+        }
+    }
+}
+
+/// A secret which has been read from the host
+#[derive(Clone, PartialEq)]
+pub(crate) struct ResolvedSecret {
+    /// Name of the secret
+    pub(crate) name: String,
+    /// The secret itself
+    pub(crate) value: String,
+    /// Where to mount the secret in the container, if it isn't
+    /// to be exposed as an environment variable
+    pub(crate) mount: Option<path::PathBuf>,
+}
+
+/// Custom debug formatter for ResolvedSecret, which keeps the secret
+/// itself out of logs
+impl fmt::Debug for ResolvedSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ResolvedSecret")
+            .field("name", &self.name)
+            .field("value", &"<redacted>")
+            .field("mount", &self.mount)
+            .finish()
+    }
+}
+
+/// Read each of the configured secrets from the keyring
+pub(crate) fn resolve_secrets(
+    secrets: &BTreeMap<String, config::Secret>,
+    keyring: &dyn Keyring,
+) -> Result<Vec<ResolvedSecret>, Error> {
+    secrets
+        .iter()
+        .map(|(name, secret)| {
+            let value = keyring.get_password(&secret.keyring)?.ok_or_else(|| {
+                FlokiUserError::SecretNotFound {
+                    name: name.clone(),
+                    service: secret.keyring.service.clone(),
+                    user: secret.keyring.user.clone(),
+                }
+            })?;
+            Ok(ResolvedSecret {
+                name: name.clone(),
+                value,
+                mount: secret.mount.clone(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A keyring backed by a map of (service, user) to password
+    struct MockKeyring(BTreeMap<(String, String), String>);
+
+    impl Keyring for MockKeyring {
+        fn get_password(&self, entry: &config::KeyringEntry) -> Result<Option<String>, Error> {
+            Ok(self
+                .0
+                .get(&(entry.service.clone(), entry.user.clone()))
+                .cloned())
+        }
+    }
+
+    /// A keyring which can't be accessed
+    struct BrokenKeyring;
+
+    impl Keyring for BrokenKeyring {
+        fn get_password(&self, entry: &config::KeyringEntry) -> Result<Option<String>, Error> {
+            Err(FlokiUserError::KeyringLookupFailed {
+                service: entry.service.clone(),
+                user: entry.user.clone(),
+                error: "keyring is locked".into(),
+            }
+            .into())
+        }
+    }
+
+    fn secret(service: &str, mount: Option<&str>) -> config::Secret {
+        config::Secret {
+            keyring: config::KeyringEntry {
+                service: service.into(),
+                user: "me".into(),
+            },
+            mount: mount.map(path::PathBuf::from),
+        }
+    }
+
+    fn keyring() -> MockKeyring {
+        let mut passwords = BTreeMap::new();
+        passwords.insert(("github".into(), "me".into()), "hunter2".into());
+        MockKeyring(passwords)
+    }
+
+    #[test]
+    fn test_resolve_secrets() -> Result<(), Error> {
+        let mut secrets = BTreeMap::new();
+        secrets.insert("TOKEN".to_string(), secret("github", None));
+        secrets.insert(
+            "token-file".to_string(),
+            secret("github", Some("/run/token")),
+        );
+
+        let resolved = resolve_secrets(&secrets, &keyring())?;
+
+        assert_eq!(
+            resolved,
+            vec![
+                ResolvedSecret {
+                    name: "TOKEN".into(),
+                    value: "hunter2".into(),
+                    mount: None,
+                },
+                ResolvedSecret {
+                    name: "token-file".into(),
+                    value: "hunter2".into(),
+                    mount: Some("/run/token".into()),
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_missing_secret() {
+        let mut secrets = BTreeMap::new();
+        secrets.insert("TOKEN".to_string(), secret("gitlab", None));
+
+        let err = resolve_secrets(&secrets, &keyring()).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::SecretNotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resolve_secret_lookup_failure() {
+        let mut secrets = BTreeMap::new();
+        secrets.insert("TOKEN".to_string(), secret("github", None));
+
+        let err = resolve_secrets(&secrets, &BrokenKeyring).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::KeyringLookupFailed { .. })
+        ));
+    }
+
+    #[test]
+    fn test_resolved_secret_debug_is_redacted() {
+        let secret = ResolvedSecret {
+            name: "TOKEN".into(),
+            value: "hunter2".into(),
+            mount: None,
+        };
+        assert!(!format!("{:?}", secret).contains("hunter2"));
+    }
+}
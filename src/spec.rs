@@ -1,23 +1,38 @@
-use crate::config::{DindConfig, FlokiConfig};
-use crate::dind::DEFAULT_DIND_IMAGE;
+use crate::command::DockerOutput;
+use crate::config::{DindConfig, DindMode, FlokiConfig};
+use crate::dind::{resolve_docker_socket, DEFAULT_DIND_IMAGE};
 use crate::environment::Environment;
 use crate::errors;
+use crate::history::{history_directory, shell_history, History};
+use crate::secrets::{resolve_secrets, ResolvedSecret, SystemKeyring};
+use crate::variables::configured_variables;
+use crate::volumes::{package_cache_volumes, readable_volume_path, resolve_overlay_volumes};
 
 use anyhow::Error;
 
 use std::collections::BTreeMap;
 use std::ffi::OsString;
+use std::net::IpAddr;
 use std::path;
+use std::time::Duration;
 
 /// Information for running docker-in-docker
-#[derive(Debug)]
-pub(crate) struct Dind {
-    /// The image to use
-    pub(crate) image: String,
+#[derive(Debug, Clone)]
+pub(crate) enum Dind {
+    /// Run a linked docker-in-docker container
+    Container {
+        /// The image to use
+        image: String,
+    },
+    /// Forward the host's docker socket
+    Socket {
+        /// Path to the socket on the host
+        path: path::PathBuf,
+    },
 }
 
 /// Information about the user
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct User {
     /// Should the user be forwarded?
     pub(crate) forward: bool,
@@ -28,14 +43,14 @@ pub(crate) struct User {
 }
 
 /// Information about the host SSH agent
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct SshAgent {
     /// Path to the agents socket
     pub(crate) path: OsString,
 }
 
 /// Paths used for running floki
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct Paths {
     /// The internal working directory
     pub(crate) internal_working_directory: path::PathBuf,
@@ -50,10 +65,17 @@ pub(crate) struct Paths {
 
 /// FlokiSpec provides a fully resolved and preprocessed block of
 /// configuration data which is clearer to construct a command from.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) struct FlokiSpec {
     /// Details of the image to use
     pub(crate) image: crate::image::Image,
+    /// The directory the image is built or loaded from - the floki root,
+    /// unless the image is reused from another project
+    pub(crate) image_root: path::PathBuf,
+    /// Commands to run on the host before the container is started
+    pub(crate) pre_run: Vec<String>,
+    /// Programs which must be on the host's PATH
+    pub(crate) requires_host_tools: Vec<String>,
     /// Commands to run on initialization
     pub(crate) init: Vec<String>,
     /// Shell to use in the environment
@@ -70,6 +92,59 @@ pub(crate) struct FlokiSpec {
     pub(crate) ssh_agent: Option<SshAgent>,
     /// Explicit docker switches to use
     pub(crate) docker_switches: Vec<String>,
+    /// Environment variables to set in the container
+    pub(crate) variables: BTreeMap<String, String>,
+    /// Secrets to make available in the container
+    pub(crate) secrets: Vec<ResolvedSecret>,
+    /// Seconds to wait for containers to stop before killing them
+    pub(crate) stop_timeout: Option<u64>,
+    /// Signal docker sends to stop the container
+    pub(crate) stop_signal: Option<String>,
+    /// The umask for the container session
+    pub(crate) umask: Option<String>,
+    /// Restart policy for containers run in the background
+    pub(crate) restart: Option<String>,
+    /// The size of `/dev/shm` in the container, e.g. `2g`
+    pub(crate) shm_size: Option<String>,
+    /// How old a locally cached tag may be before it is pulled again
+    pub(crate) refresh_tag_after: Option<Duration>,
+    /// The PID namespace for the container, e.g. `host`
+    pub(crate) pid: Option<String>,
+    /// The IPC namespace for the container, e.g. `host`
+    pub(crate) ipc: Option<String>,
+    /// The logging driver for the container
+    pub(crate) log_driver: Option<String>,
+    /// Options for the logging driver
+    pub(crate) log_opts: BTreeMap<String, String>,
+    /// The platform to run the image for, e.g. `linux/arm64`
+    pub(crate) platform: Option<String>,
+    /// What to do if the image is for another architecture
+    pub(crate) platform_check: crate::config::PlatformCheck,
+    /// DNS servers for the container
+    pub(crate) dns: Vec<String>,
+    /// Extra host to IP mappings for the container, as `name:ip`
+    pub(crate) add_hosts: Vec<String>,
+    /// Persisted shell history
+    pub(crate) history: Option<History>,
+    /// Directories in the container to add to the front of PATH
+    pub(crate) prepend_path: Vec<path::PathBuf>,
+    /// Container paths kept in docker volumes across runs
+    pub(crate) persist_paths: Vec<path::PathBuf>,
+    /// Paths in the container shadowed by empty volumes, hiding the
+    /// project's contents there
+    pub(crate) exclude: Vec<path::PathBuf>,
+    /// Where to mount the floki configuration in the container
+    pub(crate) mount_config: Option<path::PathBuf>,
+    /// The git common directory to mount, when working in a git worktree
+    pub(crate) git_common_dir: Option<path::PathBuf>,
+    /// Whether to check the docker client supports the features in use
+    pub(crate) check_docker_version: bool,
+    /// What to do with the output of the docker commands which get images
+    pub(crate) docker_output: DockerOutput,
+    /// The name to give the container, if not left to docker
+    pub(crate) container_name: Option<String>,
+    /// A host file to copy the container's output to
+    pub(crate) output: Option<path::PathBuf>,
     /// Linked docker environments
     pub(crate) dind: Option<Dind>,
     /// Paths on the host which are relevant to running
@@ -78,12 +153,27 @@ pub(crate) struct FlokiSpec {
 
 impl FlokiSpec {
     pub(crate) fn from(config: FlokiConfig, environ: Environment) -> Result<Self, Error> {
+        let variables = configured_variables(&config, &environ.floki_root)?;
+
         let dind = match config.dind {
-            DindConfig::Toggle(true) => Some(Dind {
+            DindConfig::Toggle(true) => Some(Dind::Container {
                 image: DEFAULT_DIND_IMAGE.to_string(),
             }),
             DindConfig::Toggle(false) => None,
-            DindConfig::Image { image } => Some(Dind { image }),
+            DindConfig::Image { image } => Some(Dind::Container { image }),
+            DindConfig::Mode(DindMode::Socket) => {
+                let path = resolve_docker_socket(
+                    config.docker_socket.as_deref(),
+                    environ.docker_host.as_deref(),
+                );
+                if !path.exists() {
+                    return Err(errors::FlokiUserError::DockerSocketNotFound {
+                        path: path.display().to_string(),
+                    }
+                    .into());
+                }
+                Some(Dind::Socket { path })
+            }
         };
 
         let user = User {
@@ -104,12 +194,47 @@ impl FlokiSpec {
             None
         };
 
+        validate_add_hosts(&config.add_hosts)?;
+        validate_volumes(
+            &config.volumes,
+            &environ.config_file,
+            &environ.floki_workspace,
+        )?;
+
+        // Volumes configured explicitly take precedence over caches
+        let mut volumes = package_cache_volumes(&config.cache)?;
+        volumes.extend(config.volumes);
+
+        let git_common_dir = if config.mount_git_common {
+            environ.git_common_dir.clone()
+        } else {
+            None
+        };
+
         let internal_working_directory = get_working_directory(
             &environ.current_directory,
             &environ.floki_root,
             &path::PathBuf::from(&config.mount),
         );
 
+        let history = if config.persist_history {
+            let shell = shell_history(config.shell.inner_shell());
+            if shell.is_none() {
+                warn!(
+                    "Unable to persist history for shell '{}' - bash, zsh and fish are supported",
+                    config.shell.inner_shell()
+                );
+            }
+            let directory = history_directory(&environ.floki_workspace, &environ.config_file);
+            shell.map(|shell| History { directory, shell })
+        } else {
+            None
+        };
+
+        let image_root = match &config.image_root {
+            Some(root) => root.clone(),
+            None => environ.floki_root.clone(),
+        };
         let paths = Paths {
             internal_working_directory,
             root: environ.floki_root,
@@ -119,16 +244,82 @@ impl FlokiSpec {
 
         let docker_switches = decompose_switches(&config.docker_switches)?;
 
+        let secrets = resolve_secrets(&config.secrets, &SystemKeyring)?;
+
+        if let Some(timeout) = config.stop_timeout {
+            validate_stop_timeout(timeout)?;
+        }
+
+        if let Some(signal) = &config.stop_signal {
+            validate_stop_signal(signal)?;
+        }
+
+        if let Some(umask) = &config.umask {
+            validate_umask(umask)?;
+        }
+
+        let exclude = exclude_mounts(&config.mount, &config.exclude)?;
+        validate_persist_paths(&config.persist_paths)?;
+        validate_prepend_path(&config.prepend_path)?;
+        validate_host_tools(&config.requires_host_tools)?;
+        let mount = &config.mount;
+        let prepend_path = config
+            .prepend_path
+            .iter()
+            .map(|dir| mount.join(dir))
+            .collect();
+
+        if let Some(policy) = &config.restart {
+            validate_restart_policy(policy)?;
+        }
+
+        if let Some(size) = &config.shm_size {
+            validate_size("shm_size", size)?;
+        }
+
+        if config.log_driver.is_none() && !config.log_opts.is_empty() {
+            return Err(errors::FlokiUserError::LogOptsWithoutDriver {}.into());
+        }
+
         let spec = FlokiSpec {
             image: config.image,
+            image_root,
+            pre_run: config.pre_run,
+            requires_host_tools: config.requires_host_tools,
             init: config.init,
             mount: config.mount,
             shell: config.shell,
             entrypoint,
-            volumes: config.volumes,
+            volumes,
             user,
             ssh_agent,
             docker_switches,
+            variables,
+            secrets,
+            stop_timeout: config.stop_timeout,
+            stop_signal: config.stop_signal,
+            umask: config.umask,
+            restart: config.restart,
+            shm_size: config.shm_size,
+            refresh_tag_after: config.refresh_tag_after,
+            pid: config.pid,
+            ipc: config.ipc,
+            log_driver: config.log_driver,
+            log_opts: config.log_opts,
+            platform: config.platform,
+            platform_check: config.platform_check,
+            dns: config.dns,
+            add_hosts: config.add_hosts,
+            history,
+            prepend_path,
+            persist_paths: config.persist_paths,
+            exclude,
+            mount_config: config.mount_config,
+            git_common_dir,
+            check_docker_version: true,
+            docker_output: DockerOutput::Capture,
+            container_name: None,
+            output: None,
             dind,
             paths,
         };
@@ -155,13 +346,237 @@ fn decompose_switches(specs: &[String]) -> Result<Vec<String>, Error> {
     Ok(flattened)
 }
 
+/// Check each extra host has the form `name:ip`. docker also accepts the
+/// special `host-gateway` in place of an IP address.
+fn validate_add_hosts(add_hosts: &[String]) -> Result<(), Error> {
+    for entry in add_hosts {
+        let valid = match entry.split_once(':') {
+            Some((name, ip)) => {
+                !name.is_empty() && (ip == "host-gateway" || ip.parse::<IpAddr>().is_ok())
+            }
+            None => false,
+        };
+        if !valid {
+            return Err(errors::FlokiUserError::MalformedAddHost {
+                entry: entry.clone(),
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
+/// Check a stop signal is one docker will accept: a signal number, or a
+/// signal name with or without the `SIG` prefix.
+fn validate_stop_signal(signal: &str) -> Result<(), Error> {
+    let valid = match signal.parse::<i32>() {
+        Ok(number) => (1..=64).contains(&number),
+        Err(_) => {
+            let name = signal.to_uppercase();
+            let name = if name.starts_with("SIG") {
+                name
+            } else {
+                format!("SIG{}", name)
+            };
+            name.parse::<nix::sys::signal::Signal>().is_ok()
+        }
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(errors::FlokiUserError::InvalidStopSignal {
+            signal: signal.to_string(),
+        }
+        .into())
+    }
+}
+
+/// Check a umask is an octal mode of three or four digits
+fn validate_umask(umask: &str) -> Result<(), Error> {
+    let valid = (3..=4).contains(&umask.len()) && umask.chars().all(|c| ('0'..='7').contains(&c));
+    if valid {
+        Ok(())
+    } else {
+        Err(errors::FlokiUserError::InvalidUmask {
+            umask: umask.to_string(),
+        }
+        .into())
+    }
+}
+
+/// Resolve the excluded paths to where they are in the container. Each
+/// must be a relative path within the project.
+fn exclude_mounts(
+    mount: &path::Path,
+    exclude: &[path::PathBuf],
+) -> Result<Vec<path::PathBuf>, Error> {
+    exclude
+        .iter()
+        .map(|path| {
+            let within_project = path
+                .components()
+                .all(|c| matches!(c, path::Component::Normal(_) | path::Component::CurDir));
+            if within_project && path.components().next().is_some() {
+                Ok(mount.join(path))
+            } else {
+                Err(errors::FlokiUserError::InvalidExclude {
+                    path: path.display().to_string(),
+                }
+                .into())
+            }
+        })
+        .collect()
+}
+
+/// Check required host tools are plain program names, as they are looked
+/// for in each directory on PATH
+fn validate_host_tools(tools: &[String]) -> Result<(), Error> {
+    match tools
+        .iter()
+        .find(|tool| tool.is_empty() || tool.contains('/'))
+    {
+        Some(tool) => Err(errors::FlokiUserError::InvalidHostTool { tool: tool.clone() }.into()),
+        None => Ok(()),
+    }
+}
+
+/// Check persisted paths are absolute paths in the container, with
+/// something left to name their volumes by
+fn validate_persist_paths(paths: &[path::PathBuf]) -> Result<(), Error> {
+    match paths
+        .iter()
+        .find(|path| !path.is_absolute() || readable_volume_path(path).is_empty())
+    {
+        Some(path) => Err(errors::FlokiUserError::InvalidPersistPath {
+            path: path.display().to_string(),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// Check no prepend_path entry contains ':', which would split it into
+/// several PATH entries
+fn validate_prepend_path(paths: &[path::PathBuf]) -> Result<(), Error> {
+    match paths
+        .iter()
+        .find(|path| path.to_string_lossy().contains(':'))
+    {
+        Some(path) => Err(errors::FlokiUserError::InvalidPrependPath {
+            path: path.display().to_string(),
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
+
+/// The longest stop timeout accepted. docker has no limit of its own, but
+/// anything longer is almost certainly a mistake.
+const MAX_STOP_TIMEOUT: u64 = 60 * 60;
+
+/// Check a stop timeout is a positive number of seconds, up to an hour
+fn validate_stop_timeout(timeout: u64) -> Result<(), Error> {
+    if timeout == 0 || timeout > MAX_STOP_TIMEOUT {
+        return Err(errors::FlokiUserError::InvalidStopTimeout { timeout }.into());
+    }
+    Ok(())
+}
+
+/// Parse a size as docker does, e.g. `512m`, `2g` or `1.5GiB`, into a number
+/// of bytes. Units are case-insensitive, and a bare number is in bytes.
+fn parse_size(size: &str) -> Option<u64> {
+    let size = size.trim().to_ascii_lowercase();
+    let digits = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(digits);
+    let number: f64 = number.parse().ok()?;
+    let unit = unit.trim_start();
+    let unit = unit.strip_suffix('b').unwrap_or(unit);
+    let unit = unit.strip_suffix('i').unwrap_or(unit);
+    let multiplier: u64 = match unit {
+        "" => 1,
+        "k" => 1 << 10,
+        "m" => 1 << 20,
+        "g" => 1 << 30,
+        "t" => 1 << 40,
+        "p" => 1 << 50,
+        _ => return None,
+    };
+    Some((number * multiplier as f64) as u64)
+}
+
+/// Check a size option is a size docker understands, and isn't zero
+fn validate_size(field: &str, size: &str) -> Result<(), Error> {
+    match parse_size(size) {
+        Some(bytes) if bytes > 0 => Ok(()),
+        _ => Err(errors::FlokiUserError::InvalidSize {
+            field: field.to_string(),
+            size: size.to_string(),
+        }
+        .into()),
+    }
+}
+
+/// Check a restart policy is one docker understands, including a limit on
+/// retries as in `on-failure:3`
+fn validate_restart_policy(policy: &str) -> Result<(), Error> {
+    let retries_valid = match policy.strip_prefix("on-failure:") {
+        Some(retries) => {
+            retries.chars().all(|c| c.is_ascii_digit())
+                && matches!(retries.parse::<u32>(), Ok(n) if n > 0)
+        }
+        None => false,
+    };
+    match policy {
+        "no" | "on-failure" | "always" | "unless-stopped" => Ok(()),
+        _ if retries_valid => Ok(()),
+        _ => Err(errors::FlokiUserError::InvalidRestartPolicy {
+            policy: policy.to_string(),
+        }
+        .into()),
+    }
+}
+
+/// Check volumes don't combine incompatible options
+fn validate_volumes(
+    volumes: &BTreeMap<String, crate::config::Volume>,
+    config_file: &path::Path,
+    workspace: &path::Path,
+) -> Result<(), Error> {
+    for (name, volume) in volumes.iter() {
+        if volume.source.is_some() && !volume.overlay.is_empty() {
+            return Err(
+                errors::FlokiUserError::ConflictingVolumeSources { name: name.clone() }.into(),
+            );
+        }
+    }
+
+    // The directories making up an overlay are passed to docker in a
+    // `--mount` option, where these characters separate fields
+    for overlay in resolve_overlay_volumes(config_file, workspace, volumes) {
+        let paths = overlay.lower.iter().chain([&overlay.upper, &overlay.work]);
+        for path in paths {
+            let path = path.display().to_string();
+            if path.contains(&[',', ':', '"'][..]) {
+                return Err(errors::FlokiUserError::InvalidOverlayPath {
+                    name: overlay.name.to_string(),
+                    path,
+                }
+                .into());
+            }
+        }
+    }
+    Ok(())
+}
+
 /// Determine what directory we are currently in
 fn get_working_directory(
     current_directory: &path::Path,
     floki_root: &path::Path,
     mount: &path::Path,
 ) -> path::PathBuf {
-    mount.join(current_directory.strip_prefix(&floki_root).expect(
+    mount.join(current_directory.strip_prefix(floki_root).expect(
         "failed to deduce working directory - \
          floki_root should always be an ancestor of current_directory",
     ))
@@ -170,6 +585,117 @@ fn get_working_directory(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::environment::User as EnvironmentUser;
+
+    fn test_environment() -> Environment {
+        Environment {
+            user_details: EnvironmentUser {
+                uid: nix::unistd::Uid::from_raw(1000),
+                gid: nix::unistd::Gid::from_raw(1000),
+            },
+            current_directory: "/host/project/subdir".into(),
+            floki_root: "/host/project".into(),
+            config_file: "/host/project/floki.yaml".into(),
+            ssh_agent_socket: None,
+            floki_workspace: "/home/user/.floki".into(),
+            git_common_dir: Some("/host/main/.git".into()),
+            docker_host: None,
+            default_registry: None,
+        }
+    }
+
+    fn spec_from_yaml(yaml: &str) -> Result<FlokiSpec, Error> {
+        FlokiSpec::from(serde_yaml::from_str(yaml)?, test_environment())
+    }
+
+    /// Check `validate` accepts each of `good` and rejects each of `bad`
+    fn check_validator<T: std::fmt::Debug, R>(
+        validate: impl Fn(&T) -> Result<R, Error>,
+        good: &[T],
+        bad: &[T],
+    ) {
+        for case in good {
+            assert!(validate(case).is_ok(), "{:?} should be accepted", case);
+        }
+        for case in bad {
+            assert!(validate(case).is_err(), "{:?} should be rejected", case);
+        }
+    }
+
+    #[test]
+    fn test_dind_socket() -> Result<(), Error> {
+        let socket = tempfile::NamedTempFile::new()?;
+        let yaml = format!(
+            "image: foo\ndind: socket\ndocker_socket: {}",
+            socket.path().display()
+        );
+        match spec_from_yaml(&yaml)?.dind {
+            Some(Dind::Socket { path }) => assert_eq!(path, socket.path()),
+            d => panic!("expected socket dind, got {:?}", d),
+        }
+
+        let missing = "image: foo\ndind: socket\ndocker_socket: /no/such/docker.sock";
+        let err = spec_from_yaml(missing).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::FlokiUserError>(),
+            Some(errors::FlokiUserError::DockerSocketNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_persist_history() -> Result<(), Error> {
+        assert!(spec_from_yaml("image: foo")?.history.is_none());
+        let history = spec_from_yaml("image: foo\nshell: zsh\npersist_history: true")?
+            .history
+            .unwrap();
+        assert!(history.directory.starts_with("/home/user/.floki/history"));
+        assert_eq!(
+            history.shell.location,
+            crate::history::HistoryLocation::Variable {
+                name: "HISTFILE",
+                value: "/floki-history/zsh_history".to_string()
+            }
+        );
+        assert!(
+            spec_from_yaml("image: foo\nshell: python\npersist_history: true")?
+                .history
+                .is_none()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_mount_git_common() -> Result<(), Error> {
+        assert_eq!(spec_from_yaml("image: foo")?.git_common_dir, None);
+        assert_eq!(
+            spec_from_yaml("image: foo\nmount_git_common: true")?.git_common_dir,
+            Some("/host/main/.git".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_stop_timeout() -> Result<(), Error> {
+        assert_eq!(spec_from_yaml("image: foo")?.stop_timeout, None);
+        assert_eq!(
+            spec_from_yaml("image: foo\nstop_timeout: 30")?.stop_timeout,
+            Some(30)
+        );
+        assert!(spec_from_yaml("image: foo\nstop_timeout: 0").is_err());
+        assert!(spec_from_yaml("image: foo\nstop_timeout: -1").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_stop_timeout() {
+        check_validator(
+            |timeout| validate_stop_timeout(*timeout),
+            &[1, 30, 3600],
+            &[0, 3601, u64::MAX],
+        );
+    }
+
     #[test]
     fn test_decompose_switches() -> Result<(), Error> {
         let switches = vec!["-e FOO='bar baz'".to_string()];
@@ -190,6 +716,253 @@ mod test {
         assert!(got.is_err());
     }
 
+    #[test]
+    fn test_validate_add_hosts() {
+        check_validator(
+            |host| validate_add_hosts(&[host.to_string()]),
+            &[
+                "db:10.0.0.2",
+                "db6:::1",
+                "host.docker.internal:host-gateway",
+            ],
+            &["db", "db:", ":10.0.0.2", "db:not-an-ip", "db:10.0.0"],
+        );
+    }
+
+    #[test]
+    fn test_validate_stop_signal() {
+        check_validator(
+            |signal| validate_stop_signal(signal),
+            &["SIGQUIT", "QUIT", "sigterm", "int", "9", "64"],
+            &["", "SIG", "SIGNOPE", "0", "65", "-9"],
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Some(1024));
+        assert_eq!(parse_size("64m"), Some(64 << 20));
+        assert_eq!(parse_size("2g"), Some(2 << 30));
+        assert_eq!(parse_size("2GB"), Some(2 << 30));
+        assert_eq!(parse_size("512MiB"), Some(512 << 20));
+        assert_eq!(parse_size("1.5k"), Some(1536));
+        assert_eq!(parse_size("10 m"), Some(10 << 20));
+        for bad in &["", "m", "2x", "-1g", "1.2.3g", "2 gigs"] {
+            assert_eq!(parse_size(bad), None, "{} should be rejected", bad);
+        }
+    }
+
+    #[test]
+    fn test_refresh_tag_after() -> Result<(), Error> {
+        assert_eq!(spec_from_yaml("image: foo")?.refresh_tag_after, None);
+        assert_eq!(
+            spec_from_yaml("image: foo\nrefresh_tag_after: 7d")?.refresh_tag_after,
+            Some(Duration::from_secs(7 * 24 * 60 * 60))
+        );
+        assert!(spec_from_yaml("image: foo\nrefresh_tag_after: 1y").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_shm_size() -> Result<(), Error> {
+        assert_eq!(spec_from_yaml("image: foo")?.shm_size, None);
+        assert_eq!(
+            spec_from_yaml("image: foo\nshm_size: 2g")?
+                .shm_size
+                .as_deref(),
+            Some("2g")
+        );
+        assert!(spec_from_yaml("image: foo\nshm_size: lots").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_size() {
+        check_validator(
+            |size| validate_size("shm_size", size),
+            &["2g", "64m", "1024"],
+            &["lots", "0", "0m", ""],
+        );
+    }
+
+    #[test]
+    fn test_validate_umask() {
+        check_validator(
+            |umask| validate_umask(umask),
+            &["022", "027", "0077", "777"],
+            &["", "22", "08", "0228", "00022", "u=rwx", "-022"],
+        );
+    }
+
+    #[test]
+    fn test_prepend_path() -> Result<(), Error> {
+        assert!(spec_from_yaml("image: foo")?.prepend_path.is_empty());
+        assert_eq!(
+            spec_from_yaml("image: foo\nmount: /src\nprepend_path: [bin, ./tools/bin, /opt/bin]")?
+                .prepend_path,
+            vec![
+                path::PathBuf::from("/src/bin"),
+                path::PathBuf::from("/src/./tools/bin"),
+                path::PathBuf::from("/opt/bin")
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_persist_paths() -> Result<(), Error> {
+        assert_eq!(
+            spec_from_yaml("image: foo\npersist_paths: [/opt/toolchain]")?.persist_paths,
+            vec![path::PathBuf::from("/opt/toolchain")]
+        );
+        assert!(spec_from_yaml("image: foo\npersist_paths: [opt/toolchain]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_prepend_path() {
+        check_validator(
+            |dir| validate_prepend_path(&[path::PathBuf::from(dir)]),
+            &["bin", "./tools/bin", "/opt/bin", "my tools"],
+            &["bin:tools", "/opt/bin:", ":"],
+        );
+    }
+
+    #[test]
+    fn test_validate_host_tools() {
+        check_validator(
+            |tool| validate_host_tools(&[tool.to_string()]),
+            &["git", "make", "docker-compose"],
+            &["", "/usr/bin/git", "./scripts/x", "bin/"],
+        );
+    }
+
+    #[test]
+    fn test_validate_persist_paths() {
+        check_validator(
+            |persisted| validate_persist_paths(&[path::PathBuf::from(persisted)]),
+            &["/opt/toolchain", "/.cache", "/-a"],
+            &["opt/toolchain", "./cache", "", "/", "/--", "/@/+"],
+        );
+    }
+
+    #[test]
+    fn test_exclude() -> Result<(), Error> {
+        assert!(spec_from_yaml("image: foo")?.exclude.is_empty());
+        assert_eq!(
+            spec_from_yaml("image: foo\nmount: /src\nexclude: [node_modules]")?.exclude,
+            vec![path::PathBuf::from("/src/node_modules")]
+        );
+        assert!(spec_from_yaml("image: foo\nexclude: [/etc]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_exclude_mounts() {
+        let mount = path::Path::new("/src");
+        let cases = [
+            ("node_modules", "/src/node_modules"),
+            ("build/target", "/src/build/target"),
+            ("./target", "/src/./target"),
+        ];
+        for (exclude, expected) in cases.iter() {
+            assert_eq!(
+                exclude_mounts(mount, &[path::PathBuf::from(exclude)]).unwrap(),
+                vec![path::PathBuf::from(expected)]
+            );
+        }
+        check_validator(
+            |exclude| exclude_mounts(mount, &[path::PathBuf::from(exclude)]),
+            &[],
+            &["/etc", "../sibling", "a/../../b", ""],
+        );
+    }
+
+    #[test]
+    fn test_log_opts_need_a_driver() -> Result<(), Error> {
+        let spec = spec_from_yaml("image: foo\nlog_driver: json-file\nlog_opts:\n  max-size: 10m")?;
+        assert_eq!(spec.log_driver.as_deref(), Some("json-file"));
+        assert_eq!(spec.log_opts.len(), 1);
+
+        assert!(spec_from_yaml("image: foo\nlog_driver: none")?
+            .log_opts
+            .is_empty());
+
+        let err = spec_from_yaml("image: foo\nlog_opts:\n  max-size: 10m").unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<errors::FlokiUserError>(),
+            Some(errors::FlokiUserError::LogOptsWithoutDriver {})
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_restart_policy() -> Result<(), Error> {
+        assert_eq!(spec_from_yaml("image: foo")?.restart, None);
+        assert_eq!(
+            spec_from_yaml("image: foo\nrestart: on-failure:3")?
+                .restart
+                .as_deref(),
+            Some("on-failure:3")
+        );
+        assert!(spec_from_yaml("image: foo\nrestart: sometimes").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_validate_restart_policy() {
+        check_validator(
+            |policy| validate_restart_policy(policy),
+            &[
+                "no",
+                "on-failure",
+                "always",
+                "unless-stopped",
+                "on-failure:1",
+                "on-failure:10",
+            ],
+            &[
+                "",
+                "sometimes",
+                "on-failure:",
+                "on-failure:0",
+                "on-failure:-1",
+                "on-failure:+2",
+                "on-failure:x",
+                "always:3",
+            ],
+        );
+    }
+
+    #[test]
+    fn test_conflicting_volume_sources() {
+        let yaml = "image: foo\nvolumes:\n  tools:\n    mount: /tools\n    source: tools\n    overlay:\n      - base";
+        assert!(spec_from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn test_invalid_overlay_paths() {
+        let volumes = |overlay: &str| -> BTreeMap<String, crate::config::Volume> {
+            let yaml = format!("tools:\n  mount: /tools\n  overlay:\n    - {}", overlay);
+            serde_yaml::from_str(&yaml).unwrap()
+        };
+        let config = path::Path::new("/host/project/floki.yaml");
+        let workspace = path::Path::new("/home/user/.floki");
+
+        assert!(validate_volumes(&volumes("base"), config, workspace).is_ok());
+        for bad in &["'a,b'", "'a:b'", "'a\"b'"] {
+            let error = validate_volumes(&volumes(bad), config, workspace).unwrap_err();
+            assert!(
+                error.to_string().contains("overlay volume 'tools'"),
+                "{} should be rejected",
+                bad
+            );
+        }
+        // The project directory is part of the lower directories' paths
+        let config = path::Path::new("/host/a:b/floki.yaml");
+        assert!(validate_volumes(&volumes("base"), config, workspace).is_err());
+    }
+
     #[test]
     fn test_get_working_directory() {
         let current_directory = path::PathBuf::from("/host/workingdir/");
@@ -198,7 +971,7 @@ mod test {
 
         assert!(
             get_working_directory(&current_directory, &floki_root, &mount)
-                == path::PathBuf::from("/guest/workingdir/")
+                == path::Path::new("/guest/workingdir/")
         )
     }
 }
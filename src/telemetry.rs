@@ -0,0 +1,439 @@
+//! Timing spans for floki's phases, exported to an OpenTelemetry collector.
+//!
+//! Spans are only recorded when floki is built with the `otel` feature and
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Otherwise `in_span` just runs the
+//! phase.
+
+/// The variable naming the collector to export spans to
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+static ENDPOINT_VARIABLE: &str = "OTEL_EXPORTER_OTLP_ENDPOINT";
+
+/// The variable giving headers to send to the collector, such as
+/// credentials, as `key=value` pairs separated by commas
+#[cfg_attr(not(feature = "otel"), allow(dead_code))]
+static HEADERS_VARIABLE: &str = "OTEL_EXPORTER_OTLP_HEADERS";
+
+#[cfg(not(feature = "otel"))]
+mod disabled {
+    use anyhow::Error;
+
+    /// Recording of spans for a run of floki, which is disabled
+    pub(crate) struct Session;
+
+    impl Session {
+        pub(crate) fn from_env() -> Self {
+            Session
+        }
+    }
+
+    pub(crate) fn in_span<T, F>(_name: &'static str, phase: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        phase()
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub(crate) use disabled::{in_span, Session};
+
+#[cfg(feature = "otel")]
+pub(crate) use spans::{in_span, Session};
+
+#[cfg(feature = "otel")]
+pub(crate) mod spans {
+    use super::{ENDPOINT_VARIABLE, HEADERS_VARIABLE};
+    use anyhow::Error;
+
+    use std::cell::RefCell;
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// How long to wait for the collector, in seconds
+    const EXPORT_TIMEOUT: &str = "2";
+
+    /// A finished span
+    #[derive(Debug, Clone)]
+    pub(crate) struct SpanData {
+        pub(crate) trace_id: String,
+        pub(crate) span_id: String,
+        pub(crate) parent_span_id: Option<String>,
+        pub(crate) name: &'static str,
+        pub(crate) start: SystemTime,
+        pub(crate) end: SystemTime,
+        /// The error the phase failed with, if it did
+        pub(crate) error: Option<String>,
+    }
+
+    /// Somewhere to send the spans from a run of floki
+    pub(crate) trait SpanExporter {
+        fn export(&self, spans: &[SpanData]);
+    }
+
+    /// Collects spans until the session ends
+    struct Recorder {
+        trace_id: String,
+        /// The IDs of the spans which are still open, innermost last
+        open: Vec<String>,
+        finished: Vec<SpanData>,
+        exporter: Box<dyn SpanExporter>,
+    }
+
+    thread_local! {
+        static RECORDER: RefCell<Option<Recorder>> = const { RefCell::new(None) };
+    }
+
+    /// Recording of spans for a run of floki. The spans are exported when
+    /// the session is dropped.
+    pub(crate) struct Session {
+        installed: bool,
+    }
+
+    impl Session {
+        /// Start recording to the collector named in the environment. If
+        /// none is named, nothing is recorded by this session.
+        pub(crate) fn from_env() -> Self {
+            match std::env::var(ENDPOINT_VARIABLE) {
+                Ok(endpoint) if !endpoint.is_empty() => {
+                    let headers = std::env::var(HEADERS_VARIABLE).unwrap_or_default();
+                    Self::install(Box::new(OtlpHttpExporter {
+                        endpoint,
+                        headers: parse_headers(&headers),
+                    }))
+                }
+                _ => Session { installed: false },
+            }
+        }
+
+        pub(crate) fn install(exporter: Box<dyn SpanExporter>) -> Self {
+            let recorder = Recorder {
+                trace_id: new_id(32),
+                open: Vec::new(),
+                finished: Vec::new(),
+                exporter,
+            };
+            RECORDER.with(|cell| *cell.borrow_mut() = Some(recorder));
+            Session { installed: true }
+        }
+    }
+
+    impl Drop for Session {
+        fn drop(&mut self) {
+            if !self.installed {
+                return;
+            }
+            if let Some(recorder) = RECORDER.with(|cell| cell.borrow_mut().take()) {
+                recorder.exporter.export(&recorder.finished);
+            }
+        }
+    }
+
+    /// Run a phase of floki in a span, recording how long it took and
+    /// whether it succeeded
+    pub(crate) fn in_span<T, F>(name: &'static str, phase: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let opened = RECORDER.with(|cell| {
+            cell.borrow_mut().as_mut().map(|recorder| {
+                let span_id = new_id(16);
+                let parent_span_id = recorder.open.last().cloned();
+                recorder.open.push(span_id.clone());
+                (span_id, parent_span_id, SystemTime::now())
+            })
+        });
+
+        let result = phase();
+
+        if let Some((span_id, parent_span_id, start)) = opened {
+            RECORDER.with(|cell| {
+                if let Some(recorder) = cell.borrow_mut().as_mut() {
+                    recorder.open.pop();
+                    let span = SpanData {
+                        trace_id: recorder.trace_id.clone(),
+                        span_id,
+                        parent_span_id,
+                        name,
+                        start,
+                        end: SystemTime::now(),
+                        error: result.as_ref().err().map(|e| e.to_string()),
+                    };
+                    recorder.finished.push(span);
+                }
+            });
+        }
+        result
+    }
+
+    /// A random ID of `length` hex digits
+    fn new_id(length: usize) -> String {
+        let mut id = uuid::Uuid::new_v4().to_simple().to_string();
+        id.truncate(length);
+        id
+    }
+
+    /// Exports spans as OTLP JSON over HTTP. The request is made with
+    /// curl, which deals with TLS, proxies and the rest of HTTP.
+    struct OtlpHttpExporter {
+        endpoint: String,
+        /// Extra request headers, as `Name: value` lines
+        headers: Vec<String>,
+    }
+
+    impl SpanExporter for OtlpHttpExporter {
+        fn export(&self, spans: &[SpanData]) {
+            let body = otlp_json(spans).to_string();
+            match post_json(&traces_url(&self.endpoint), &self.headers, &body) {
+                Ok(()) => debug!("Exported {} spans to {}", spans.len(), self.endpoint),
+                Err(e) => warn!("Unable to export spans to {}: {}", self.endpoint, e),
+            }
+        }
+    }
+
+    fn unix_nanos(time: SystemTime) -> String {
+        time.duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+            .to_string()
+    }
+
+    /// Encode spans as an OTLP trace export request
+    pub(crate) fn otlp_json(spans: &[SpanData]) -> serde_json::Value {
+        let spans: Vec<serde_json::Value> = spans
+            .iter()
+            .map(|span| {
+                // Status codes are 1 for OK and 2 for an error
+                let status = match &span.error {
+                    Some(message) => serde_json::json!({ "code": 2, "message": message }),
+                    None => serde_json::json!({ "code": 1 }),
+                };
+                let mut encoded = serde_json::json!({
+                    "traceId": span.trace_id,
+                    "spanId": span.span_id,
+                    "name": span.name,
+                    "kind": 1,
+                    "startTimeUnixNano": unix_nanos(span.start),
+                    "endTimeUnixNano": unix_nanos(span.end),
+                    "status": status,
+                });
+                if let Some(parent) = &span.parent_span_id {
+                    encoded["parentSpanId"] = parent.clone().into();
+                }
+                encoded
+            })
+            .collect();
+
+        serde_json::json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [{
+                        "key": "service.name",
+                        "value": { "stringValue": "floki" },
+                    }],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": "floki" },
+                    "spans": spans,
+                }],
+            }],
+        })
+    }
+
+    /// Where traces are sent, given the collector's base endpoint
+    pub(crate) fn traces_url(endpoint: &str) -> String {
+        format!("{}/v1/traces", endpoint.trim_end_matches('/'))
+    }
+
+    /// Turn the `key=value,...` pairs of `OTEL_EXPORTER_OTLP_HEADERS` into
+    /// header lines. Entries without a key are skipped.
+    pub(crate) fn parse_headers(value: &str) -> Vec<String> {
+        value
+            .split(',')
+            .filter_map(|entry| {
+                let (key, value) = entry.split_once('=')?;
+                let key = key.trim();
+                if key.is_empty() {
+                    None
+                } else {
+                    Some(format!("{}: {}", key, value.trim()))
+                }
+            })
+            .collect()
+    }
+
+    /// POST a JSON body to the collector. The headers are given to curl on
+    /// its stdin, so credentials in them don't appear in its arguments.
+    fn post_json(url: &str, headers: &[String], body: &str) -> Result<(), Error> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(body.as_bytes())?;
+        file.flush()?;
+
+        let mut child = Command::new("curl")
+            .args(["--silent", "--show-error", "--fail"])
+            .args(["--max-time", EXPORT_TIMEOUT])
+            .args(["--header", "Content-Type: application/json"])
+            .args(["--header", "@-"])
+            .arg("--data-binary")
+            .arg(format!("@{}", file.path().display()))
+            .arg(url)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            for header in headers {
+                writeln!(stdin, "{}", header)?;
+            }
+        }
+
+        let output = child.wait_with_output()?;
+        if output.status.success() {
+            Ok(())
+        } else {
+            Err(anyhow::anyhow!(
+                "curl failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) mod test {
+        use super::*;
+        use crate::errors::FlokiUserError;
+
+        use std::io::Read;
+        use std::net::TcpListener;
+        use std::rc::Rc;
+        use std::time::Duration;
+
+        /// Keeps exported spans in memory
+        #[derive(Clone, Default)]
+        pub(crate) struct InMemoryExporter(pub(crate) Rc<RefCell<Vec<SpanData>>>);
+
+        impl SpanExporter for InMemoryExporter {
+            fn export(&self, spans: &[SpanData]) {
+                self.0.borrow_mut().extend_from_slice(spans);
+            }
+        }
+
+        #[test]
+        fn test_spans() {
+            let exporter = InMemoryExporter::default();
+            {
+                let _session = Session::install(Box::new(exporter.clone()));
+                let result: Result<(), Error> = in_span("launch", || {
+                    in_span("pull", || Ok(()))?;
+                    in_span("run", || Err(FlokiUserError::LocalFlagRemoved {}.into()))
+                });
+                assert!(result.is_err());
+                // Nothing is exported until the session ends
+                assert!(exporter.0.borrow().is_empty());
+            }
+
+            let spans = exporter.0.borrow();
+            let names: Vec<&str> = spans.iter().map(|span| span.name).collect();
+            assert_eq!(names, vec!["pull", "run", "launch"]);
+
+            let launch = &spans[2];
+            assert_eq!(launch.parent_span_id, None);
+            assert!(launch.error.is_some());
+            assert_eq!(spans[0].parent_span_id.as_ref(), Some(&launch.span_id));
+            assert_eq!(spans[0].error, None);
+            assert_eq!(spans[1].parent_span_id.as_ref(), Some(&launch.span_id));
+            assert!(spans[1].error.is_some());
+            assert!(spans.iter().all(|span| span.trace_id == launch.trace_id));
+            assert!(spans.iter().all(|span| span.end >= span.start));
+        }
+
+        #[test]
+        fn test_no_session() -> Result<(), Error> {
+            assert_eq!(in_span("run", || Ok(3))?, 3);
+            // A session which isn't exporting anywhere records nothing
+            let _session = Session { installed: false };
+            assert_eq!(in_span("run", || Ok(4))?, 4);
+            RECORDER.with(|cell| assert!(cell.borrow().is_none()));
+            Ok(())
+        }
+
+        #[test]
+        fn test_otlp_json() {
+            let start = UNIX_EPOCH + Duration::from_secs(1);
+            let span = SpanData {
+                trace_id: "0af7651916cd43dd8448eb211c80319c".into(),
+                span_id: "b7ad6b7169203331".into(),
+                parent_span_id: None,
+                name: "pull",
+                start,
+                end: start + Duration::from_millis(5),
+                error: Some("failed".into()),
+            };
+            let encoded = otlp_json(&[span]);
+            let spans = &encoded["resourceSpans"][0]["scopeSpans"][0]["spans"];
+            assert_eq!(spans[0]["name"], "pull");
+            assert_eq!(spans[0]["startTimeUnixNano"], "1000000000");
+            assert_eq!(spans[0]["endTimeUnixNano"], "1005000000");
+            assert_eq!(spans[0]["status"]["code"], 2);
+            assert!(spans[0].get("parentSpanId").is_none());
+        }
+
+        #[test]
+        fn test_traces_url() {
+            assert_eq!(
+                traces_url("http://collector:4318"),
+                "http://collector:4318/v1/traces"
+            );
+            assert_eq!(
+                traces_url("https://localhost:4000/otlp/"),
+                "https://localhost:4000/otlp/v1/traces"
+            );
+        }
+
+        #[test]
+        fn test_parse_headers() {
+            assert_eq!(
+                parse_headers("api-key=secret, x-team = floki"),
+                vec!["api-key: secret", "x-team: floki"]
+            );
+            assert_eq!(
+                parse_headers("Authorization=Basic dXNlcjpwYXNz=="),
+                vec!["Authorization: Basic dXNlcjpwYXNz=="]
+            );
+            assert!(parse_headers("").is_empty());
+            assert!(parse_headers("novalue,=nokey").is_empty());
+        }
+
+        #[test]
+        fn test_post_json() -> Result<(), Error> {
+            // A collector which reads one request, and answers it with a
+            // chunked response before closing the connection
+            let listener = TcpListener::bind("127.0.0.1:0")?;
+            let url = traces_url(&format!("http://{}", listener.local_addr()?));
+            let collector = std::thread::spawn(move || -> std::io::Result<String> {
+                let (mut stream, _) = listener.accept()?;
+                let mut request = Vec::new();
+                let mut buffer = [0; 4096];
+                while !String::from_utf8_lossy(&request).ends_with("{}") {
+                    let read = stream.read(&mut buffer)?;
+                    if read == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buffer[..read]);
+                }
+                stream.write_all(
+                    b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n2\r\n{}\r\n0\r\n\r\n",
+                )?;
+                Ok(String::from_utf8_lossy(&request).to_string())
+            });
+
+            post_json(&url, &["api-key: secret".to_string()], "{}")?;
+            let request = collector.join().unwrap()?;
+            assert!(request.starts_with("POST /v1/traces HTTP/1.1\r\n"));
+            assert!(request.contains("\r\napi-key: secret\r\n"));
+            assert!(request.contains("\r\nContent-Type: application/json\r\n"));
+            assert!(request.ends_with("\r\n\r\n{}"));
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,282 @@
+/// Environment variables set in the floki container from configuration
+use crate::config::FlokiConfig;
+use crate::errors::{FlokiError, FlokiUserError};
+use anyhow::Error;
+
+use std::collections::BTreeMap;
+use std::path;
+use std::str::FromStr;
+
+/// The proxy variables forwarded by `proxy_from_host`
+static PROXY_VARIABLES: &[&str] = &[
+    "HTTP_PROXY",
+    "HTTPS_PROXY",
+    "NO_PROXY",
+    "http_proxy",
+    "https_proxy",
+    "no_proxy",
+];
+
+/// Resolve the variables configured for a project, forwarding variables
+/// from floki's own environment. A relative `env_file` is taken relative
+/// to the project root.
+pub(crate) fn configured_variables(
+    config: &FlokiConfig,
+    root: &path::Path,
+) -> Result<BTreeMap<String, String>, Error> {
+    let env_file = config.env_file.as_ref().map(|file| root.join(file));
+    resolve_variables(
+        &config.env,
+        &forwarded_names(config, root)?,
+        env_file.as_deref(),
+        |name| std::env::var(name).ok(),
+    )
+}
+
+/// The names of variables to forward from the host: those in `forward_env`,
+/// those listed in `env_passthrough_file`, and the proxy variables if
+/// `proxy_from_host` is set
+fn forwarded_names(config: &FlokiConfig, root: &path::Path) -> Result<Vec<String>, Error> {
+    let mut names = config.forward_env.clone();
+    if let Some(file) = &config.env_passthrough_file {
+        names.extend(read_passthrough_file(&root.join(file))?);
+    }
+    if config.proxy_from_host {
+        names.extend(PROXY_VARIABLES.iter().map(|name| name.to_string()));
+    }
+    Ok(names)
+}
+
+/// Read a file listing the names of variables to forward, one per line.
+/// Blank lines and lines starting with `#` are ignored.
+pub(crate) fn read_passthrough_file(file: &path::Path) -> Result<Vec<String>, Error> {
+    let contents =
+        std::fs::read_to_string(file).map_err(|e| FlokiError::ProblemReadingPassthroughFile {
+            name: file.display().to_string(),
+            error: e,
+        })?;
+    Ok(parse_passthrough_file(&contents))
+}
+
+fn parse_passthrough_file(contents: &str) -> Vec<String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+/// Resolve the environment variables to set in the container. Variables
+/// from `env_file` are overridden by `env`, which are in turn overridden
+/// by variables forwarded from the host. Forwarded variables which aren't
+/// set on the host are skipped.
+pub(crate) fn resolve_variables<F>(
+    env: &BTreeMap<String, String>,
+    forward_env: &[String],
+    env_file: Option<&path::Path>,
+    host_variable: F,
+) -> Result<BTreeMap<String, String>, Error>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let mut variables = BTreeMap::new();
+
+    if let Some(file) = env_file {
+        let contents =
+            std::fs::read_to_string(file).map_err(|e| FlokiError::ProblemReadingEnvFile {
+                name: file.display().to_string(),
+                error: e,
+            })?;
+        variables.extend(parse_env_file(file, &contents)?);
+    }
+
+    variables.extend(env.iter().map(|(k, v)| (k.clone(), v.clone())));
+
+    for name in forward_env.iter() {
+        match host_variable(name) {
+            Some(value) => {
+                variables.insert(name.clone(), value);
+            }
+            None => debug!("Not forwarding {} - it is not set on the host", name),
+        }
+    }
+
+    Ok(variables)
+}
+
+/// Parse an environment file of `KEY=VALUE` lines. Blank lines and lines
+/// starting with `#` are ignored.
+fn parse_env_file(file: &path::Path, contents: &str) -> Result<Vec<(String, String)>, Error> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                Ok((key.trim().to_string(), value.to_string()))
+            }
+            _ => Err(FlokiUserError::MalformedEnvFile {
+                file: file.display().to_string(),
+                line: line.to_string(),
+            }
+            .into()),
+        })
+        .collect()
+}
+
+/// Host shells which we can write export statements for
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportShell {
+    Posix,
+    Fish,
+    Powershell,
+}
+
+impl FromStr for ExportShell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sh" | "bash" | "zsh" => Ok(ExportShell::Posix),
+            "fish" => Ok(ExportShell::Fish),
+            "powershell" => Ok(ExportShell::Powershell),
+            _ => Err(format!("unsupported shell '{}'", s)),
+        }
+    }
+}
+
+/// A statement setting an environment variable in the given shell, with
+/// the value quoted so it is taken literally
+pub(crate) fn export_statement(shell: ExportShell, name: &str, value: &str) -> String {
+    match shell {
+        ExportShell::Posix => format!("export {}='{}'", name, value.replace('\'', "'\\''")),
+        ExportShell::Fish => format!(
+            "set -gx {} '{}'",
+            name,
+            value.replace('\\', "\\\\").replace('\'', "\\'")
+        ),
+        ExportShell::Powershell => format!("$env:{} = '{}'", name, value.replace('\'', "''")),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_env_file() -> Result<(), Error> {
+        let contents = "# A comment\n\nFOO=bar\nBAZ = qux=quux\nEMPTY=\n";
+        assert_eq!(
+            parse_env_file(path::Path::new(".env"), contents)?,
+            vec![
+                ("FOO".to_string(), "bar".to_string()),
+                ("BAZ".to_string(), " qux=quux".to_string()),
+                ("EMPTY".to_string(), "".to_string()),
+            ]
+        );
+        assert!(parse_env_file(path::Path::new(".env"), "NOT_AN_ASSIGNMENT").is_err());
+        assert!(parse_env_file(path::Path::new(".env"), "=value").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_passthrough_file() {
+        let contents = "# Proxies\nhttp_proxy\n\n  https_proxy  \n#NOT_THIS\n";
+        assert_eq!(
+            parse_passthrough_file(contents),
+            vec!["http_proxy".to_string(), "https_proxy".to_string()]
+        );
+    }
+
+    #[test]
+    fn test_forwarded_names() -> Result<(), Error> {
+        let root = tempfile::tempdir()?;
+        std::fs::write(root.path().join("passthrough"), "CI\nhttp_proxy\n")?;
+
+        let config: FlokiConfig = serde_yaml::from_str(
+            "image: foo\nforward_env: [HOME]\nenv_passthrough_file: passthrough",
+        )?;
+        assert_eq!(
+            forwarded_names(&config, root.path())?,
+            vec![
+                "HOME".to_string(),
+                "CI".to_string(),
+                "http_proxy".to_string()
+            ]
+        );
+
+        let config: FlokiConfig =
+            serde_yaml::from_str("image: foo\nenv_passthrough_file: missing")?;
+        assert!(forwarded_names(&config, root.path()).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_proxy_from_host() -> Result<(), Error> {
+        let root = path::Path::new("/project");
+        let config: FlokiConfig =
+            serde_yaml::from_str("image: foo\nforward_env: [HOME]\nproxy_from_host: true")?;
+        assert_eq!(
+            forwarded_names(&config, root)?,
+            vec![
+                "HOME",
+                "HTTP_PROXY",
+                "HTTPS_PROXY",
+                "NO_PROXY",
+                "http_proxy",
+                "https_proxy",
+                "no_proxy"
+            ]
+        );
+
+        let config: FlokiConfig = serde_yaml::from_str("image: foo")?;
+        assert!(forwarded_names(&config, root)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_variables() -> Result<(), Error> {
+        let mut file = tempfile::NamedTempFile::new()?;
+        writeln!(file, "FROM_FILE=file\nOVERRIDDEN=file")?;
+
+        let mut env = BTreeMap::new();
+        env.insert("OVERRIDDEN".to_string(), "env".to_string());
+        env.insert("FORWARDED".to_string(), "env".to_string());
+        let forward_env = vec!["FORWARDED".to_string(), "UNSET".to_string()];
+
+        let host = |name: &str| match name {
+            "FORWARDED" => Some("host".to_string()),
+            _ => None,
+        };
+
+        let variables = resolve_variables(&env, &forward_env, Some(file.path()), host)?;
+        let expected: BTreeMap<String, String> = vec![
+            ("FORWARDED".to_string(), "host".to_string()),
+            ("FROM_FILE".to_string(), "file".to_string()),
+            ("OVERRIDDEN".to_string(), "env".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(variables, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_export_statement() {
+        let value = "it's a $HOME \\ test";
+        assert_eq!(
+            export_statement(ExportShell::Posix, "FOO", value),
+            "export FOO='it'\\''s a $HOME \\ test'"
+        );
+        assert_eq!(
+            export_statement(ExportShell::Fish, "FOO", value),
+            "set -gx FOO 'it\\'s a $HOME \\\\ test'"
+        );
+        assert_eq!(
+            export_statement(ExportShell::Powershell, "FOO", value),
+            "$env:FOO = 'it''s a $HOME \\ test'"
+        );
+    }
+}
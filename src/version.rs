@@ -0,0 +1,187 @@
+/// Checks that the docker client is new enough for the features in use
+use crate::errors::FlokiUserError;
+use anyhow::Error;
+
+use std::cell::RefCell;
+use std::fmt;
+use std::process::{Command, Stdio};
+
+/// A docker client version, e.g. 20.10
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct DockerVersion {
+    pub(crate) major: u32,
+    pub(crate) minor: u32,
+}
+
+impl fmt::Display for DockerVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Versions before 23.0 were numbered by year and month, as in 20.10
+        if self.major < 23 {
+            write!(f, "{}.{:02}", self.major, self.minor)
+        } else {
+            write!(f, "{}.{}", self.major, self.minor)
+        }
+    }
+}
+
+impl DockerVersion {
+    /// Parse a version as reported by docker, such as `24.0.7`,
+    /// `20.10.21+dfsg1` or `17.05.0-ce`
+    pub(crate) fn parse(version: &str) -> Option<Self> {
+        let mut parts = version.trim().split('.');
+        let major = parts.next()?.parse().ok()?;
+        let minor: String = parts
+            .next()?
+            .chars()
+            .take_while(char::is_ascii_digit)
+            .collect();
+        Some(DockerVersion {
+            major,
+            minor: minor.parse().ok()?,
+        })
+    }
+}
+
+/// Features floki uses which need a minimum docker version
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum DockerFeature {
+    /// `--platform`, from `platform`
+    Platform,
+    /// `host-gateway` in `add_hosts`
+    HostGateway,
+    /// `--stop-timeout`, from `stop_timeout`
+    StopTimeout,
+}
+
+impl DockerFeature {
+    fn minimum_version(self) -> DockerVersion {
+        let (major, minor) = match self {
+            DockerFeature::Platform => (20, 10),
+            DockerFeature::HostGateway => (20, 10),
+            DockerFeature::StopTimeout => (17, 5),
+        };
+        DockerVersion { major, minor }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            DockerFeature::Platform => "platform",
+            DockerFeature::HostGateway => "host-gateway in add_hosts",
+            DockerFeature::StopTimeout => "stop_timeout",
+        }
+    }
+}
+
+/// Check the detected docker version supports a feature
+pub(crate) fn check_feature(feature: DockerFeature, detected: DockerVersion) -> Result<(), Error> {
+    let required = feature.minimum_version();
+    if detected < required {
+        return Err(FlokiUserError::DockerTooOld {
+            feature: feature.description().to_string(),
+            required: required.to_string(),
+            detected: detected.to_string(),
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Check the docker client supports each of the features in use. If the
+/// version can't be determined, the check is skipped.
+pub(crate) fn check_docker_version(features: &[DockerFeature]) -> Result<(), Error> {
+    if features.is_empty() {
+        return Ok(());
+    }
+    match docker_version() {
+        Some(detected) => {
+            for feature in features.iter() {
+                check_feature(*feature, detected)?;
+            }
+            Ok(())
+        }
+        None => {
+            warn!("Unable to determine the docker version - skipping version checks");
+            Ok(())
+        }
+    }
+}
+
+thread_local! {
+    static DOCKER_VERSION: RefCell<Option<Option<DockerVersion>>> =
+        const { RefCell::new(None) };
+}
+
+/// The version of the docker client. docker is only asked once per run of
+/// floki.
+fn docker_version() -> Option<DockerVersion> {
+    DOCKER_VERSION.with(|cached| *cached.borrow_mut().get_or_insert_with(probe_docker_version))
+}
+
+fn probe_docker_version() -> Option<DockerVersion> {
+    // The client version is reported even if the daemon can't be reached
+    let output = Command::new("docker")
+        .args(["version", "--format", "{{.Client.Version}}"])
+        .stdin(Stdio::null())
+        .stderr(Stdio::null())
+        .output()
+        .ok()?;
+    let version = DockerVersion::parse(&String::from_utf8_lossy(&output.stdout));
+    debug!("Detected docker version: {:?}", version);
+    version
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        let version = |major, minor| Some(DockerVersion { major, minor });
+        assert_eq!(DockerVersion::parse("24.0.7\n"), version(24, 0));
+        assert_eq!(DockerVersion::parse("20.10.21+dfsg1"), version(20, 10));
+        assert_eq!(DockerVersion::parse("17.05.0-ce"), version(17, 5));
+        assert_eq!(DockerVersion::parse("1.13.1"), version(1, 13));
+        assert_eq!(DockerVersion::parse(""), None);
+        assert_eq!(DockerVersion::parse("dev"), None);
+    }
+
+    #[test]
+    fn test_check_feature() {
+        let cases = [
+            (DockerFeature::Platform, "24.0.7", true),
+            (DockerFeature::Platform, "20.10.0", true),
+            (DockerFeature::Platform, "19.03.15", false),
+            (DockerFeature::HostGateway, "20.10.21", true),
+            (DockerFeature::HostGateway, "18.09.1", false),
+            (DockerFeature::StopTimeout, "17.05.0-ce", true),
+            (DockerFeature::StopTimeout, "17.03.2-ce", false),
+            (DockerFeature::StopTimeout, "1.13.1", false),
+        ];
+        for (feature, version, supported) in cases.iter() {
+            let detected = DockerVersion::parse(version).unwrap();
+            assert_eq!(
+                check_feature(*feature, detected).is_ok(),
+                *supported,
+                "{:?} with docker {}",
+                feature,
+                version
+            );
+        }
+    }
+
+    #[test]
+    fn test_version_in_error() {
+        let detected = DockerVersion::parse("19.03.15").unwrap();
+        let err = check_feature(DockerFeature::Platform, detected).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::DockerTooOld { required, detected, .. })
+                if required == "20.10" && detected == "19.03"
+        ));
+        assert_eq!(DockerVersion::parse("24.0.7").unwrap().to_string(), "24.0");
+        assert_eq!(
+            DockerVersion::parse("17.05.0").unwrap().to_string(),
+            "17.05"
+        );
+    }
+}
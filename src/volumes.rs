@@ -4,9 +4,59 @@ use std::{collections::BTreeMap, os::unix::prelude::OsStrExt};
 use sha2::{Digest, Sha256};
 
 use crate::config::Volume;
+use crate::errors::FlokiUserError;
+use anyhow::Error;
 
 static VOLUME_DIRECTORY: &str = "volumes/";
 
+/// Local overrides kept alongside a floki configuration file, which are
+/// mounted with it by `mount_config`
+static LOCAL_CONFIG_FILE: &str = "floki.local.yaml";
+
+/// Package manager caches which can be enabled with `cache`, and where
+/// they live in the official images for each ecosystem
+static PACKAGE_CACHES: &[(&str, &str)] = &[
+    ("cargo", "/usr/local/cargo/registry"),
+    ("go", "/go/pkg/mod"),
+    ("npm", "/root/.npm"),
+    ("pip", "/root/.cache/pip"),
+];
+
+/// An overlay volume, layering host directories at a mount path
+#[derive(Debug, PartialEq)]
+pub(crate) struct OverlayVolume<'a> {
+    /// The name of the volume in the configuration
+    pub(crate) name: &'a str,
+    /// The host directories to layer, lowest first
+    pub(crate) lower: Vec<path::PathBuf>,
+    /// Host directory holding changes made in the container
+    pub(crate) upper: path::PathBuf,
+    /// Host directory used internally by the overlay filesystem
+    pub(crate) work: path::PathBuf,
+    /// Where the overlay is mounted in the container
+    pub(crate) mount: &'a path::PathBuf,
+}
+
+impl<'a> OverlayVolume<'a> {
+    /// The `--mount` specification for an overlayfs-backed docker volume
+    pub(crate) fn mount_spec(&self) -> String {
+        // overlayfs stacks lower directories with the highest first
+        let lower: Vec<String> = self
+            .lower
+            .iter()
+            .rev()
+            .map(|l| l.display().to_string())
+            .collect();
+        format!(
+            "type=volume,dst={},volume-driver=local,volume-opt=type=overlay,volume-opt=device=overlay,\"volume-opt=o=lowerdir={},upperdir={},workdir={}\"",
+            self.mount.display(),
+            lower.join(":"),
+            self.upper.display(),
+            self.work.display()
+        )
+    }
+}
+
 pub(crate) fn resolve_volume_mounts<'a>(
     config_filepath: &path::Path,
     work_path: &path::Path,
@@ -14,6 +64,7 @@ pub(crate) fn resolve_volume_mounts<'a>(
 ) -> Vec<(path::PathBuf, &'a path::PathBuf)> {
     volumes
         .iter()
+        .filter(|(_, volume)| volume.overlay.is_empty() && volume.source.is_none())
         .map(|(name, volume)| {
             (
                 cache_path(work_path, config_filepath, name, volume),
@@ -23,6 +74,157 @@ pub(crate) fn resolve_volume_mounts<'a>(
         .collect()
 }
 
+/// Resolve volumes which mount a host directory. Missing sources are an
+/// error, unless the volume is optional in which case it is skipped.
+pub(crate) fn resolve_host_mounts<'a>(
+    config_filepath: &path::Path,
+    volumes: &'a BTreeMap<String, Volume>,
+) -> Result<Vec<(path::PathBuf, &'a path::PathBuf)>, Error> {
+    let config_directory = config_filepath.parent().unwrap_or(config_filepath);
+    let mut mounts = Vec::new();
+    for (name, volume) in volumes.iter() {
+        if let Some(source) = &volume.source {
+            let source = config_directory.join(source);
+            if source.exists() {
+                mounts.push((source, &volume.mount));
+            } else if volume.optional {
+                debug!(
+                    "Skipping optional volume '{}' - {} does not exist",
+                    name,
+                    source.display()
+                );
+            } else {
+                return Err(FlokiUserError::VolumeSourceNotFound {
+                    name: name.clone(),
+                    path: source.display().to_string(),
+                }
+                .into());
+            }
+        }
+    }
+    Ok(mounts)
+}
+
+pub(crate) fn resolve_overlay_volumes<'a>(
+    config_filepath: &path::Path,
+    work_path: &path::Path,
+    volumes: &'a BTreeMap<String, Volume>,
+) -> Vec<OverlayVolume<'a>> {
+    let config_directory = config_filepath.parent().unwrap_or(config_filepath);
+    volumes
+        .iter()
+        .filter(|(_, volume)| !volume.overlay.is_empty())
+        .map(|(name, volume)| {
+            let cache = cache_path(work_path, config_filepath, name, volume);
+            OverlayVolume {
+                name,
+                lower: volume
+                    .overlay
+                    .iter()
+                    .map(|l| config_directory.join(l))
+                    .collect(),
+                upper: cache.join("upper"),
+                work: cache.join("work"),
+                mount: &volume.mount,
+            }
+        })
+        .collect()
+}
+
+/// The host files to mount for `mount_config`, and where to mount each
+/// under the container directory: the configuration file, and the local
+/// configuration beside it if there is one
+pub(crate) fn config_mounts(
+    config_filepath: &path::Path,
+    mount: &path::Path,
+) -> Vec<(path::PathBuf, path::PathBuf)> {
+    let mut mounts = Vec::new();
+    if let Some(name) = config_filepath.file_name() {
+        mounts.push((config_filepath.to_path_buf(), mount.join(name)));
+    }
+    if let Some(directory) = config_filepath.parent() {
+        let local = directory.join(LOCAL_CONFIG_FILE);
+        if local.is_file() && local != config_filepath {
+            mounts.push((local, mount.join(LOCAL_CONFIG_FILE)));
+        }
+    }
+    mounts
+}
+
+/// Expand package manager cache names into shared volumes, named
+/// `cache-<name>`, at the caches' conventional paths
+pub(crate) fn package_cache_volumes(caches: &[String]) -> Result<BTreeMap<String, Volume>, Error> {
+    caches
+        .iter()
+        .map(|name| {
+            let mount = PACKAGE_CACHES
+                .iter()
+                .find(|(cache, _)| cache == name)
+                .map(|(_, mount)| path::PathBuf::from(mount))
+                .ok_or_else(|| FlokiUserError::UnknownCache {
+                    name: name.clone(),
+                    supported: PACKAGE_CACHES
+                        .iter()
+                        .map(|(cache, _)| *cache)
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                })?;
+            let volume = Volume {
+                shared: true,
+                mount,
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
+            };
+            Ok((format!("cache-{}", name), volume))
+        })
+        .collect()
+}
+
+/// Docker named volumes for `persist_paths`, paired with the container path
+/// each is mounted at
+pub(crate) fn persisted_path_volumes<'a>(
+    config_filepath: &path::Path,
+    paths: &'a [path::PathBuf],
+) -> Vec<(String, &'a path::PathBuf)> {
+    paths
+        .iter()
+        .map(|path| (persisted_volume_name(config_filepath, path), path))
+        .collect()
+}
+
+/// The name of the docker volume persisting a container path for a project.
+/// This is the same on every run, and is prefixed by a hash of the
+/// configuration file like the backing directories of unshared volumes.
+/// The readable form of the path loses characters, so it is followed by a
+/// hash of the path itself to keep the names of different paths apart.
+fn persisted_volume_name(config_filepath: &path::Path, path: &path::Path) -> String {
+    format!(
+        "floki-{}-{}-{}",
+        &hash_path(config_filepath)[..16],
+        readable_volume_path(path),
+        &hash_path(path)[..8]
+    )
+}
+
+/// A path with the characters docker doesn't allow in volume names replaced
+/// by '-', and without any leading or trailing '-'
+pub(crate) fn readable_volume_path(path: &path::Path) -> String {
+    let readable: String = path
+        .to_string_lossy()
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '_' {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+    readable.trim_matches('-').to_string()
+}
+
 fn cache_path(
     work_path: &path::Path,
     config_filepath: &path::Path,
@@ -41,7 +243,7 @@ fn prefix_cache(shared: bool, config_filepath: &path::Path) -> String {
     }
 }
 
-fn hash_path(path: &path::Path) -> String {
+pub(crate) fn hash_path(path: &path::Path) -> String {
     let mut hasher = Sha256::new();
     hasher.update(path.as_os_str().as_bytes());
     format!("{:x}", hasher.finalize())
@@ -61,6 +263,10 @@ mod test {
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
         let cache_2 = cache_path(
@@ -70,6 +276,10 @@ mod test {
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
 
@@ -85,6 +295,10 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
         let cache_2 = cache_path(
@@ -94,6 +308,10 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
 
@@ -109,6 +327,10 @@ mod test {
             &Volume {
                 shared: true,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
         let cache_local = cache_path(
@@ -118,6 +340,10 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
 
@@ -133,6 +359,10 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
         let cache_local = cache_path(
@@ -142,6 +372,10 @@ mod test {
             &Volume {
                 shared: false,
                 mount: "/".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
             },
         );
 
@@ -157,4 +391,218 @@ mod test {
             "04820cace8be1a2e8057c92231963c269cc0fd0fef01fd3fdf2deaffb62dc48d"
         );
     }
+
+    #[test]
+    fn test_resolve_overlay_volumes() {
+        let mut volumes = BTreeMap::new();
+        volumes.insert(
+            "plain".to_string(),
+            Volume {
+                shared: false,
+                mount: "/plain".into(),
+                overlay: Vec::new(),
+                source: None,
+                optional: false,
+                chown: false,
+            },
+        );
+        volumes.insert(
+            "tools".to_string(),
+            Volume {
+                shared: true,
+                mount: "/opt/tools".into(),
+                overlay: vec!["base".into(), "/abs/plugin".into()],
+                source: None,
+                optional: false,
+                chown: false,
+            },
+        );
+
+        let config = Path::new("/floki/root/floki.yaml");
+        let work_path = Path::new("/work");
+        let target = path::PathBuf::from("/opt/tools");
+
+        assert_eq!(
+            resolve_overlay_volumes(config, work_path, &volumes),
+            vec![OverlayVolume {
+                name: "tools",
+                lower: vec!["/floki/root/base".into(), "/abs/plugin".into()],
+                upper: "/work/volumes/tools/upper".into(),
+                work: "/work/volumes/tools/work".into(),
+                mount: &target,
+            }]
+        );
+        let mounts = resolve_volume_mounts(config, work_path, &volumes);
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].1, Path::new("/plain"));
+    }
+
+    #[test]
+    fn test_overlay_mount_spec() {
+        let target = path::PathBuf::from("/opt/tools");
+        let overlay = OverlayVolume {
+            name: "tools",
+            lower: vec!["/base".into(), "/plugin".into()],
+            upper: "/work/upper".into(),
+            work: "/work/work".into(),
+            mount: &target,
+        };
+        assert_eq!(
+            overlay.mount_spec(),
+            "type=volume,dst=/opt/tools,volume-driver=local,volume-opt=type=overlay,\
+             volume-opt=device=overlay,\
+             \"volume-opt=o=lowerdir=/plugin:/base,upperdir=/work/upper,workdir=/work/work\""
+        );
+    }
+
+    fn host_volume(source: &path::Path, optional: bool) -> Volume {
+        Volume {
+            shared: false,
+            mount: "/mnt".into(),
+            overlay: Vec::new(),
+            source: Some(source.to_path_buf()),
+            optional,
+            chown: false,
+        }
+    }
+
+    #[test]
+    fn test_resolve_host_mounts() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        std::fs::create_dir(tmp_dir.path().join("cache"))?;
+        let config = tmp_dir.path().join("floki.yaml");
+
+        let mut volumes = BTreeMap::new();
+        volumes.insert("cache".to_string(), host_volume(Path::new("cache"), false));
+        volumes.insert(
+            "missing".to_string(),
+            host_volume(Path::new("missing"), true),
+        );
+
+        let mounts = resolve_host_mounts(&config, &volumes)?;
+        assert_eq!(mounts.len(), 1);
+        assert_eq!(mounts[0].0, tmp_dir.path().join("cache"));
+        assert_eq!(mounts[0].1, Path::new("/mnt"));
+
+        // Host mounts aren't backed by floki managed directories
+        assert!(resolve_volume_mounts(&config, Path::new("work_path"), &volumes).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_resolve_host_mounts_missing_source() -> Result<(), Error> {
+        let tmp_dir = tempfile::TempDir::new()?;
+        let config = tmp_dir.path().join("floki.yaml");
+
+        let mut volumes = BTreeMap::new();
+        volumes.insert(
+            "missing".to_string(),
+            host_volume(Path::new("missing"), false),
+        );
+
+        let err = resolve_host_mounts(&config, &volumes).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<FlokiUserError>(),
+            Some(FlokiUserError::VolumeSourceNotFound { .. })
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_package_cache_volumes() -> Result<(), Error> {
+        let caches = vec!["cargo".into(), "go".into(), "npm".into(), "pip".into()];
+        let volumes = package_cache_volumes(&caches)?;
+        let mounts: Vec<(&str, &path::Path)> = volumes
+            .iter()
+            .map(|(name, volume)| (name.as_str(), volume.mount.as_path()))
+            .collect();
+        assert_eq!(
+            mounts,
+            vec![
+                ("cache-cargo", Path::new("/usr/local/cargo/registry")),
+                ("cache-go", Path::new("/go/pkg/mod")),
+                ("cache-npm", Path::new("/root/.npm")),
+                ("cache-pip", Path::new("/root/.cache/pip")),
+            ]
+        );
+        assert!(volumes.values().all(|volume| volume.shared));
+        Ok(())
+    }
+
+    #[test]
+    fn test_persisted_volume_name() {
+        let config = Path::new("/floki/root/floki.yaml");
+        let name = persisted_volume_name(config, Path::new("/opt/toolchain"));
+        assert_eq!(
+            name,
+            format!(
+                "floki-{}-opt-toolchain-{}",
+                &hash_path(config)[..16],
+                &hash_path(Path::new("/opt/toolchain"))[..8]
+            )
+        );
+        // The same path gets the same volume on every run
+        assert_eq!(
+            persisted_volume_name(config, Path::new("/opt/toolchain")),
+            name
+        );
+        // ...but not in other projects
+        assert_ne!(
+            persisted_volume_name(Path::new("/other/floki.yaml"), Path::new("/opt/toolchain")),
+            name
+        );
+        assert!(
+            persisted_volume_name(config, Path::new("/root/.cache/my tool"))
+                .contains("-root-.cache-my-tool-")
+        );
+    }
+
+    #[test]
+    fn test_persisted_volume_names_dont_collide() {
+        let config = Path::new("/floki/root/floki.yaml");
+        // Each of these reads as "opt-tool-chain"
+        let names: Vec<String> = ["/opt/tool-chain", "/opt/tool/chain", "/opt/tool chain/"]
+            .iter()
+            .map(|path| persisted_volume_name(config, Path::new(path)))
+            .collect();
+        assert_ne!(names[0], names[1]);
+        assert_ne!(names[0], names[2]);
+        assert_ne!(names[1], names[2]);
+    }
+
+    #[test]
+    fn test_persisted_path_volumes() {
+        let config = Path::new("/floki/root/floki.yaml");
+        let paths = vec![
+            path::PathBuf::from("/opt/toolchain"),
+            path::PathBuf::from("/data"),
+        ];
+        let volumes = persisted_path_volumes(config, &paths);
+        let prefix = format!("floki-{}", &hash_path(config)[..16]);
+        assert_eq!(
+            volumes,
+            vec![
+                (
+                    format!("{}-opt-toolchain-{}", prefix, &hash_path(&paths[0])[..8]),
+                    &paths[0]
+                ),
+                (
+                    format!("{}-data-{}", prefix, &hash_path(&paths[1])[..8]),
+                    &paths[1]
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_package_cache() {
+        let err = package_cache_volumes(&["maven".into()]).unwrap_err();
+        match err.downcast_ref::<FlokiUserError>() {
+            Some(FlokiUserError::UnknownCache { name, supported }) => {
+                assert_eq!(name, "maven");
+                assert_eq!(supported, "cargo, go, npm, pip");
+            }
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
 }